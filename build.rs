@@ -0,0 +1,9 @@
+fn main() {
+    println!("cargo:rerun-if-changed=schema/veles.capnp");
+
+    capnpc::CompilerCommand::new()
+        .src_prefix("schema")
+        .file("schema/veles.capnp")
+        .run()
+        .expect("compiling schema/veles.capnp");
+}
@@ -1,13 +1,15 @@
-use std::{io::Write, path::PathBuf};
+use std::{collections::HashSet, io::Write, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use log::{error, LevelFilter};
 use simple_logger::SimpleLogger;
 
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use veles::{
     client::{ChangeListEntry, IndexState, VelesClient},
+    config::VelesConfig,
     error::VelesError,
+    repo::FsckIssue,
 };
 
 #[derive(Parser)]
@@ -43,8 +45,26 @@ enum Command {
         description: String,
     },
     Changelog,
-    Sync,
-    Server,
+    Cat {
+        #[arg(short, long, default_value = "main")]
+        revision: String,
+
+        paths: Vec<String>,
+    },
+    Files {
+        #[arg(short, long, default_value = "main")]
+        revision: String,
+    },
+    Sync {
+        #[arg(short, long, default_value = "127.0.0.1:7420")]
+        remote: String,
+    },
+    Fsck,
+    Upgrade,
+    Server {
+        #[arg(short, long, default_value = "127.0.0.1:7420")]
+        bind: String,
+    },
 }
 
 #[derive(Subcommand)]
@@ -54,7 +74,8 @@ enum TaskCommand {
 }
 
 fn main() {
-    let cli = Cli::parse();
+    let args = expand_aliases(std::env::args().collect());
+    let cli = Cli::parse_from(args);
 
     let log_level = if cli.debug {
         LevelFilter::Info
@@ -73,8 +94,12 @@ fn main() {
         Command::Task { command: _ } => todo!(),
         Command::Submit { description } => submit(description),
         Command::Changelog => changelog(),
-        Command::Sync => cli.sync(),
-        Command::Server => todo!(),
+        Command::Cat { revision, paths } => cat(revision, paths),
+        Command::Files { revision } => files(revision),
+        Command::Sync { remote } => sync(remote),
+        Command::Fsck => fsck(),
+        Command::Upgrade => upgrade(),
+        Command::Server { bind } => server(bind),
     };
 
     if let Err(e) = result {
@@ -83,9 +108,93 @@ fn main() {
         if cli.debug {
             error!("{:?}", e);
         }
+
+        std::process::exit(1);
+    }
+}
+
+/// Expands a user-defined `[alias]` from the merged config into the real
+/// argument vector it stands for, the way Cargo resolves aliased
+/// subcommands. If the first argument already names a built-in command, or
+/// there's no matching alias, `args` is returned unchanged (after printing a
+/// "did you mean" suggestion for an unrecognized, unaliased command).
+fn expand_aliases(mut args: Vec<String>) -> Vec<String> {
+    let builtins = Cli::command();
+    let config = VelesConfig::load(&VelesConfig::default_layers(&PathBuf::from(".veles")))
+        .unwrap_or_default();
+
+    let mut seen = HashSet::new();
+
+    loop {
+        let Some(command_name) = args.get(1).cloned() else {
+            return args;
+        };
+
+        if builtins.find_subcommand(&command_name).is_some() {
+            return args;
+        }
+
+        if !seen.insert(command_name.clone()) {
+            eprintln!("error: alias `{}` is part of a cycle", command_name);
+            std::process::exit(1);
+        }
+
+        let expansion = config.get_list("alias", &command_name);
+        if expansion.is_empty() {
+            suggest_command(&command_name, &builtins);
+            return args;
+        }
+
+        let mut expanded = vec![args[0].clone()];
+        expanded.extend(expansion);
+        expanded.extend(args[2..].iter().cloned());
+        args = expanded;
+    }
+}
+
+/// Prints a "did you mean" suggestion for an unrecognized command, picking
+/// the closest built-in subcommand by Levenshtein distance. Nothing is
+/// printed when no subcommand is within a distance of 3.
+fn suggest_command(name: &str, builtins: &clap::Command) {
+    let closest = builtins
+        .get_subcommands()
+        .map(|sub| (sub.get_name(), levenshtein_distance(name, sub.get_name())))
+        .min_by_key(|(_, distance)| *distance);
+
+    if let Some((candidate, distance)) = closest {
+        if distance <= 3 {
+            eprintln!(
+                "error: no such command `{}` -- did you mean `{}`?",
+                name, candidate
+            );
+        }
     }
 }
 
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut prev_diagonal = row[0];
+        row[0] = i;
+
+        for j in 1..=b.len() {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let current = (row[j] + 1)
+                .min(row[j - 1] + 1)
+                .min(prev_diagonal + cost);
+
+            prev_diagonal = row[j];
+            row[j] = current;
+        }
+    }
+
+    row[b.len()]
+}
+
 fn init() -> Result<(), VelesError> {
     let result = VelesClient::init();
 
@@ -192,12 +301,83 @@ fn submit(description: String) -> Result<(), VelesError> {
     Ok(())
 }
 
-impl Cli {
-    pub fn sync(&self) -> Result<(), VelesError> {
-        let client = VelesClient::new()?;
+fn cat(revision: String, paths: Vec<String>) -> Result<(), VelesError> {
+    let client = VelesClient::new()?;
+    let result = client.cat(&revision, &paths)?;
 
-        
+    let stdout = std::io::stdout();
+    let mut handle = stdout.lock();
+    for (_, content) in &result.found {
+        handle.write_all(content)?;
+    }
 
-        Ok(())
+    for path in &result.missing {
+        eprintln!("{}: not tracked at revision {}", path, revision);
     }
+
+    Ok(())
+}
+
+fn files(revision: String) -> Result<(), VelesError> {
+    let client = VelesClient::new()?;
+
+    for path in client.files(&revision)? {
+        println!("{}", path);
+    }
+
+    Ok(())
+}
+
+fn sync(remote: String) -> Result<(), VelesError> {
+    let client = VelesClient::new()?;
+    client.sync(&remote)
+}
+
+fn fsck() -> Result<(), VelesError> {
+    let client = VelesClient::new()?;
+    let issues = client.fsck()?;
+
+    let mut corrupt = 0;
+    let mut missing = 0;
+    let mut orphaned = 0;
+
+    for issue in &issues {
+        match issue {
+            FsckIssue::Corrupt(hash) => {
+                corrupt += 1;
+                println!("corrupt: {}", hash);
+            }
+            FsckIssue::Missing(hash) => {
+                missing += 1;
+                println!("missing: {}", hash);
+            }
+            FsckIssue::Orphaned(hash) => {
+                orphaned += 1;
+                println!("orphaned: {}", hash);
+            }
+        }
+    }
+
+    println!(
+        "{} corrupt, {} missing, {} orphaned",
+        corrupt, missing, orphaned
+    );
+
+    if corrupt > 0 || missing > 0 {
+        return Err(VelesError::CorruptedData);
+    }
+
+    Ok(())
+}
+
+fn upgrade() -> Result<(), VelesError> {
+    let client = VelesClient::new()?;
+    let version = client.upgrade()?;
+
+    println!("Repository is now at format version {}.", version);
+    Ok(())
+}
+
+fn server(bind: String) -> Result<(), VelesError> {
+    veles::protocol::serve(&bind)
 }
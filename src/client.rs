@@ -1,30 +1,47 @@
 use std::{
-    collections::HashMap,
-    fs::{self, File, OpenOptions},
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
     io::Read,
-    path::{PathBuf},
-    time::SystemTime,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use log::info;
+use ring::digest;
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::VelesConfig, error::VelesError, protocol::LocalTransport, Changeset, DirIterator,
-    Finalize, VelesChange,
+    config::VelesConfig,
+    error::VelesError,
+    format,
+    protocol::{LocalTransport, RemoteTransport},
+    repo::{CatResult, FsckIssue},
+    util::{content_similarity, detect_content_type},
+    Change, Changeset, DirIterator, FileMeta, VelesChange,
 };
 
+/// The minimum shingle-similarity for an added file to be recorded as a
+/// copy/rename of a missing one rather than an unrelated add.
+const RENAME_SIMILARITY_THRESHOLD: f64 = 0.5;
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct VelesIndex {
     timestamp: SystemTime,
     index: HashMap<PathBuf, VelesIndexMeta>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 struct VelesIndexMeta {
     created: SystemTime,
     modified: SystemTime,
+    size: u64,
+    /// The file's content hash as of `modified`, recorded whenever the file
+    /// is read, so that a later size/mtime change can be checked against it
+    /// to tell a real edit apart from a timestamp-only one.
+    hash: Option<String>,
+    content_type: String,
+    is_text: bool,
     state: IndexState,
 }
 
@@ -37,6 +54,8 @@ pub enum IndexState {
 
 impl VelesIndex {
     pub fn load() -> Result<VelesIndex, VelesError> {
+        format::require_current(&PathBuf::from(".veles"))?;
+
         info!("Loading index at .veles/index");
 
         let index_path = PathBuf::from(".veles/index");
@@ -61,26 +80,56 @@ impl VelesIndex {
         let iter = DirIterator::from_ignorefile(".", ".velesignore", false)?;
         for path in iter {
             let metadata = fs::metadata(&path)?;
-
-            // if let Some(indexed) = index.index.get(&path) {
-            //     if indexed.modified == metadata.modified()? {
-            //         println!("Unchanged: {:?}", path);
-            //     } else {
-            //         println!("Modified: {:?}", path);
-            //     }
-            // } else {
-            //     println!("New: {:?}", path);
-            // }
-
-            if !index.index.contains_key(&path) {
-                index.index.insert(
-                    path.clone(),
-                    VelesIndexMeta {
-                        created: metadata.created()?,
-                        modified: metadata.modified()?,
-                        state: IndexState::Untracked,
-                    },
-                );
+            let size = metadata.len();
+            let mtime = metadata.modified()?;
+
+            // Unchanged size+mtime is taken as unchanged content without
+            // rereading the file. Only when one of those differs from the
+            // indexed record do we read the file and hash it, to tell a real
+            // modification apart from a mtime bump with no content change
+            // (e.g. a checkout that preserves bytes but not timestamps).
+            match index.index.get(&path).cloned() {
+                Some(existing) if existing.size == size && existing.modified == mtime => {}
+                Some(existing) => {
+                    let content = fs::read(&path)?;
+                    let hash = sha256_hex(&content);
+                    let (content_type, is_text) = detect_content_type(&path, &content);
+                    let unchanged = existing.hash.as_deref() == Some(hash.as_str());
+
+                    index.index.insert(
+                        path.clone(),
+                        VelesIndexMeta {
+                            created: existing.created,
+                            modified: mtime,
+                            size,
+                            hash: Some(hash),
+                            content_type,
+                            is_text,
+                            state: if unchanged {
+                                existing.state
+                            } else {
+                                IndexState::Untracked
+                            },
+                        },
+                    );
+                }
+                None => {
+                    let content = fs::read(&path)?;
+                    let (content_type, is_text) = detect_content_type(&path, &content);
+
+                    index.index.insert(
+                        path.clone(),
+                        VelesIndexMeta {
+                            created: metadata.created()?,
+                            modified: mtime,
+                            size,
+                            hash: Some(sha256_hex(&content)),
+                            content_type,
+                            is_text,
+                            state: IndexState::Untracked,
+                        },
+                    );
+                }
             }
         }
 
@@ -113,7 +162,11 @@ pub struct VelesClient {
 impl VelesClient {
     pub fn init() -> Result<(), VelesError> {
         let path = PathBuf::from(".veles");
-        Ok(fs::create_dir_all(path)?)
+        fs::create_dir_all(&path)?;
+
+        // A freshly initialized repo has nothing to migrate, so it starts
+        // out at the current version rather than going through `upgrade`.
+        format::write_version(&path, format::CURRENT_VERSION)
     }
 
     pub fn new() -> Result<VelesClient, VelesError> {
@@ -123,8 +176,7 @@ impl VelesClient {
             return Err(VelesError::NotInitialized);
         }
 
-        let config_path = repo_path.join("config");
-        let config = VelesConfig::load(&config_path)?;
+        let config = VelesConfig::load(&VelesConfig::default_layers(&repo_path))?;
 
         Ok(VelesClient { config })
     }
@@ -146,9 +198,9 @@ impl VelesClient {
     }
 
     pub fn config(&mut self, username: Option<String>) -> Result<&VelesConfig, VelesError> {
-        if username.is_some() {
+        if let Some(username) = username {
             let path = PathBuf::from(".veles/config");
-            self.config.user.name = username;
+            self.config.set("user", "name", username);
             self.config.save(&path)?;
         }
 
@@ -174,6 +226,7 @@ impl VelesClient {
     pub fn submit(&self, description: String) -> Result<i64, VelesError> {
         let mut index = VelesIndex::load()?;
         let transport = LocalTransport::new()?;
+        let previous_tree = transport.read_tree()?;
 
         let added: Vec<&PathBuf> = index
             .index
@@ -182,17 +235,47 @@ impl VelesClient {
             .map(|(key, _)| key)
             .collect();
 
-        let owner = self.config.user.name.clone().unwrap_or_default();
+        let added_paths: HashSet<String> = added
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
 
-        let mut changes = Vec::new();
-        for path in added {
-            let mut writer = transport.send_object()?;
-            let mut reader = File::open(path)?;
+        // Anything the previous tree tracked that is no longer on disk (and
+        // isn't itself being re-added under the same path) is a candidate
+        // source for a rename/copy detected below.
+        let mut rename_candidates: Vec<(String, String)> = previous_tree
+            .into_iter()
+            .filter(|(path, _)| !added_paths.contains(path) && !PathBuf::from(path).exists())
+            .map(|(path, entry)| (path, entry.hash))
+            .collect();
 
-            std::io::copy(&mut reader, &mut writer)?;
+        let owner = self
+            .config
+            .get_string("user", "name")
+            .unwrap_or_default()
+            .to_string();
 
-            let hash = writer.finalize()?;
-            changes.push((path.to_string_lossy().to_string(), hash));
+        let mut changes = Vec::new();
+        for path in added {
+            let path_str = path.to_string_lossy().to_string();
+            let content = fs::read(path)?;
+            let hash = transport.send_revision(&path_str, &content)?;
+            let meta = file_meta(path, &content)?;
+
+            let source = find_rename_source(&transport, &mut rename_candidates, &hash, &content)?;
+            changes.push(match source {
+                Some(from) => Change::Rename {
+                    from,
+                    to: path_str,
+                    hash,
+                    meta,
+                },
+                None => Change::Add {
+                    path: path_str,
+                    hash,
+                    meta,
+                },
+            });
         }
 
         let changeset = Changeset {
@@ -228,9 +311,52 @@ impl VelesClient {
         Ok(result)
     }
 
-    pub fn sync(&self) -> Result<(), VelesError> {
-        let index = VelesIndex::load()?;
-        let transport = LocalTransport::new()?;
+    /// Pushes locally-added objects to `remote_addr` and reports changesets
+    /// present there that aren't in the local changelog yet.
+    ///
+    /// Remote changesets missing locally are only reported; nothing is
+    /// pulled into the local changelog.
+    pub fn sync(&self, remote_addr: &str) -> Result<(), VelesError> {
+        let local = LocalTransport::new()?;
+        let remote = RemoteTransport::connect(remote_addr)?;
+
+        let local_changesets = local.changesets()?;
+        let remote_changesets = remote.list_changesets()?;
+
+        let already_pushed = |local: &VelesChange| {
+            remote_changesets
+                .iter()
+                .any(|remote| remote.tree_hash == local.tree_hash)
+        };
+
+        if let Some(latest) = local_changesets.last() {
+            if !already_pushed(latest) {
+                let tree = local.read_tree()?;
+
+                remote.submit_start(&latest.user, &latest.description)?;
+                for (path, entry) in &tree {
+                    let content = local.read_object(&entry.hash)?;
+                    remote.file_write(path, &content)?;
+                }
+                remote.submit_finalize()?;
+            }
+        }
+
+        let unseen: Vec<&VelesChange> = remote_changesets
+            .iter()
+            .filter(|remote| {
+                !local_changesets
+                    .iter()
+                    .any(|local| local.tree_hash == remote.tree_hash)
+            })
+            .collect();
+
+        for changeset in unseen {
+            println!(
+                "Remote changeset {} by {} not yet in local changelog: {}",
+                changeset.id, changeset.user, changeset.description
+            );
+        }
 
         Ok(())
     }
@@ -239,4 +365,84 @@ impl VelesClient {
         let transport = LocalTransport::new()?;
         transport.changesets()
     }
+
+    pub fn cat(&self, revision: &str, paths: &[String]) -> Result<CatResult, VelesError> {
+        let transport = LocalTransport::new()?;
+        transport.cat(revision, paths)
+    }
+
+    pub fn files(&self, revision: &str) -> Result<Vec<String>, VelesError> {
+        let transport = LocalTransport::new()?;
+        transport.files(revision)
+    }
+
+    pub fn fsck(&self) -> Result<Vec<FsckIssue>, VelesError> {
+        let transport = LocalTransport::new()?;
+        transport.fsck()
+    }
+
+    /// Migrates the repo's on-disk format up to the current version,
+    /// returning the version it ended up at. Safe to call on an
+    /// already-current repo.
+    pub fn upgrade(&self) -> Result<u32, VelesError> {
+        format::upgrade(&PathBuf::from(".veles"))
+    }
+}
+
+/// Builds the [`FileMeta`] recorded alongside an added or renamed file: its
+/// size and modification time as of this submit, and its sniffed content
+/// type.
+fn file_meta(path: &Path, content: &[u8]) -> Result<FileMeta, VelesError> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (content_type, is_text) = detect_content_type(path, content);
+
+    Ok(FileMeta {
+        size: metadata.len(),
+        mtime,
+        content_type,
+        is_text,
+    })
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    hex::encode(digest::digest(&digest::SHA256, content))
+}
+
+/// Looks for a rename/copy source for a newly added file among
+/// `candidates` (paths missing from the working tree since the previous
+/// changeset), preferring an exact content match and otherwise the most
+/// similar candidate above [`RENAME_SIMILARITY_THRESHOLD`]. The matched
+/// candidate, if any, is removed from `candidates` so it can't be claimed
+/// as the source of more than one added file.
+fn find_rename_source(
+    transport: &LocalTransport,
+    candidates: &mut Vec<(String, String)>,
+    hash: &str,
+    content: &[u8],
+) -> Result<Option<String>, VelesError> {
+    if let Some(pos) = candidates.iter().position(|(_, candidate_hash)| candidate_hash == hash) {
+        return Ok(Some(candidates.remove(pos).0));
+    }
+
+    let mut best: Option<(usize, f64)> = None;
+
+    for (i, (_, candidate_hash)) in candidates.iter().enumerate() {
+        let Ok(candidate_content) = transport.read_object(candidate_hash) else {
+            continue;
+        };
+
+        let similarity = content_similarity(content, &candidate_content);
+        if similarity >= RENAME_SIMILARITY_THRESHOLD
+            && best.map_or(true, |(_, best_similarity)| similarity > best_similarity)
+        {
+            best = Some((i, similarity));
+        }
+    }
+
+    Ok(best.map(|(i, _)| candidates.remove(i).0))
 }
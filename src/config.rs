@@ -1,45 +1,215 @@
 use core::fmt;
-use std::{fs, path::Path};
-
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use crate::error::VelesError;
 
-#[derive(Serialize, Deserialize)]
-pub struct VelesConfig {
-    pub user: User,
-}
+/// A single `[section]` of key/value pairs.
+pub type ConfigSection = HashMap<String, String>;
 
-#[derive(Serialize, Deserialize)]
-pub struct User {
-    pub name: Option<String>,
+/// A merged, layered Veles configuration.
+///
+/// Configuration is assembled from one or more INI-style files, read in
+/// precedence order so that later layers override earlier ones. Each layer
+/// may pull in additional files with `%include <path>` and remove keys
+/// inherited from earlier layers with `%unset <key>`.
+#[derive(Default)]
+pub struct VelesConfig {
+    sections: HashMap<String, ConfigSection>,
+    origins: HashMap<(String, String), PathBuf>,
 }
 
 impl VelesConfig {
-    pub fn load(path: &Path) -> Result<VelesConfig, VelesError> {
-        if !path.exists() {
-            Ok(VelesConfig {
-                user: User { name: None },
-            })
-        } else {
-            let contents = fs::read_to_string(path)?;
-            let config = toml::from_str(&contents)?;
+    /// Loads and merges the given layers in order, with later paths taking
+    /// precedence over earlier ones. Missing files are silently skipped so
+    /// callers can pass optional layers (e.g. a system config that may not
+    /// exist) without checking first.
+    pub fn load(paths: &[PathBuf]) -> Result<VelesConfig, VelesError> {
+        let mut config = VelesConfig::default();
+
+        for path in paths {
+            if path.exists() {
+                config.merge_file(path)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The default layered config paths, in precedence order: a system-wide
+    /// file, the current user's file (from `$HOME`), and the per-repo
+    /// override under `.veles/`.
+    pub fn default_layers(repo_path: &Path) -> Vec<PathBuf> {
+        let mut layers = vec![PathBuf::from("/etc/veles/config")];
+
+        if let Ok(home) = std::env::var("HOME") {
+            layers.push(PathBuf::from(home).join(".velesconfig"));
+        }
+
+        layers.push(repo_path.join("config"));
+        layers
+    }
+
+    /// Merges a single config file (and anything it `%include`s) into this
+    /// config, with its keys taking precedence over anything already set.
+    fn merge_file(&mut self, path: &Path) -> Result<(), VelesError> {
+        self.merge_file_at_depth(path, 0)
+    }
+
+    fn merge_file_at_depth(&mut self, path: &Path, depth: usize) -> Result<(), VelesError> {
+        // Guard against `%include` cycles.
+        if depth > 16 {
+            return Err(VelesError::CorruptedData);
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut section = String::new();
+        let mut last_key: Option<String> = None;
+
+        for raw_line in contents.lines() {
+            let trimmed = raw_line.trim_end();
+
+            if trimmed.trim_start().starts_with('#') || trimmed.trim_start().starts_with(';') {
+                continue;
+            }
+
+            if trimmed.trim().is_empty() {
+                continue;
+            }
+
+            // Indented lines continue the previous value.
+            if (raw_line.starts_with(' ') || raw_line.starts_with('\t')) && last_key.is_some() {
+                let key = last_key.clone().unwrap();
+                let entry = self.sections.entry(section.clone()).or_default();
+                if let Some(existing) = entry.get_mut(&key) {
+                    existing.push('\n');
+                    existing.push_str(trimmed.trim());
+                    self.origins
+                        .insert((section.clone(), key), path.to_path_buf());
+                }
+                continue;
+            }
 
-            Ok(config)
+            let line = trimmed.trim();
+
+            if let Some(rest) = line.strip_prefix("%include ") {
+                let include_path = base_dir.join(rest.trim());
+                if include_path.exists() {
+                    self.merge_file_at_depth(&include_path, depth + 1)?;
+                }
+                last_key = None;
+                continue;
+            }
+
+            if let Some(rest) = line.strip_prefix("%unset ") {
+                let key = rest.trim();
+                let (unset_section, unset_key) = split_key(&section, key);
+                if let Some(entry) = self.sections.get_mut(&unset_section) {
+                    entry.remove(&unset_key);
+                }
+                self.origins.remove(&(unset_section, unset_key));
+                last_key = None;
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                last_key = None;
+                continue;
+            }
+
+            if let Some(eq) = line.find('=') {
+                let key = line[..eq].trim().to_string();
+                let value = line[eq + 1..].trim().to_string();
+                self.sections
+                    .entry(section.clone())
+                    .or_default()
+                    .insert(key.clone(), value);
+                self.origins
+                    .insert((section.clone(), key.clone()), path.to_path_buf());
+                last_key = Some(key);
+            }
         }
+
+        Ok(())
+    }
+
+    /// Returns a single value from `section.key`, if set.
+    pub fn get_string(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections.get(section)?.get(key).map(String::as_str)
+    }
+
+    /// Returns `section.key` split on newlines and whitespace, for values
+    /// built up from continuation lines (e.g. a list of remotes).
+    pub fn get_list(&self, section: &str, key: &str) -> Vec<String> {
+        self.get_string(section, key)
+            .map(|value| {
+                value
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Sets `section.key = value` in the in-memory config, overriding any
+    /// merged-in value from an earlier layer. The key's origin is cleared,
+    /// since the new value isn't attributed to any layer file until saved.
+    pub fn set(&mut self, section: &str, key: &str, value: String) {
+        self.sections
+            .entry(section.to_string())
+            .or_default()
+            .insert(key.to_string(), value);
+        self.origins
+            .remove(&(section.to_string(), key.to_string()));
+    }
+
+    /// Returns the layer file that last set `section.key` in the merged
+    /// config, for a future `config --show-origin`.
+    pub fn get_origin(&self, section: &str, key: &str) -> Option<&Path> {
+        self.origins
+            .get(&(section.to_string(), key.to_string()))
+            .map(PathBuf::as_path)
     }
 
+    /// Writes the merged config out as a flat INI file. This does not
+    /// preserve `%include`/`%unset` directives; it is used to persist the
+    /// per-repo override layer.
     pub fn save(&self, path: &Path) -> Result<(), VelesError> {
-        let contents = toml::to_string(&self)?;
-        fs::write(path, &contents)?;
+        fs::write(path, self.to_string())?;
 
         Ok(())
     }
 }
 
+fn split_key(current_section: &str, key: &str) -> (String, String) {
+    match key.split_once('.') {
+        Some((section, key)) => (section.to_string(), key.to_string()),
+        None => (current_section.to_string(), key.to_string()),
+    }
+}
+
 impl fmt::Display for VelesConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let str = toml::to_string(&self).unwrap();
-        write!(f, "{}", str)
+        let mut sections: Vec<&String> = self.sections.keys().collect();
+        sections.sort();
+
+        for section in sections {
+            writeln!(f, "[{}]", section)?;
+
+            let mut items: Vec<(&String, &String)> = self.sections[section].iter().collect();
+            items.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (key, value) in items {
+                writeln!(f, "{} = {}", key, value)?;
+            }
+        }
+
+        Ok(())
     }
 }
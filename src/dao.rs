@@ -18,6 +18,12 @@ pub struct Changeset {
     pub tree_hash: String,
 }
 
+/// The schema version this build of veles expects `.veles/veles.db3` to be
+/// at, tracked via SQLite's `user_version` pragma. Bump this and add a step
+/// to [`migrate`] whenever the schema changes (e.g. a new `changesets`
+/// column), rather than changing [`VelesDAO::initialize`] in place.
+const DB_VERSION: i32 = 1;
+
 pub struct VelesDAO {
     db: rusqlite::Connection,
 }
@@ -28,12 +34,17 @@ impl VelesDAO {
         let initialized = path.exists();
         let db = rusqlite::Connection::open(".veles/veles.db3")?;
 
-        if !initialized {
-            let result = VelesDAO::initialize(&db);
-            if result.is_err() {
+        let result = if !initialized {
+            VelesDAO::initialize(&db).and_then(|_| set_user_version(&db, DB_VERSION))
+        } else {
+            migrate(&db)
+        };
+
+        if let Err(err) = result {
+            if !initialized {
                 let _ = fs::remove_file(path);
-                return Err(result.unwrap_err());
             }
+            return Err(err);
         }
 
         Ok(VelesDAO { db })
@@ -67,6 +78,16 @@ impl VelesDAO {
             (),
         )?;
 
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS renames (
+                changeset_id INTEGER NOT NULL,
+                from_path TEXT NOT NULL,
+                to_path TEXT NOT NULL,
+                FOREIGN KEY(changeset_id) REFERENCES changesets(changeset_id)
+            )",
+            (),
+        )?;
+
         Ok(())
     }
 
@@ -93,6 +114,26 @@ impl VelesDAO {
         Ok(task)
     }
 
+    pub fn get_changeset(&self, changeset_id: i32) -> Result<Changeset, VelesError> {
+        let mut statement = self.db.prepare("SELECT * FROM changesets WHERE changeset_id = ?")?;
+        let changeset = statement.query_row([changeset_id], |row| {
+            Ok(Changeset {
+                changeset_id: row.get(0)?,
+                previous_changeset: row.get(1)?,
+                task_id: row.get(2)?,
+                user: row.get(3)?,
+                description: row.get(4)?,
+                tree_hash: row.get(5)?,
+            })
+        });
+
+        match changeset {
+            Ok(changeset) => Ok(changeset),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(VelesError::NotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     pub fn get_latest_changeset(&self, task_id: i32) -> Result<Option<Changeset>, VelesError> {
         let mut statement = self.db.prepare(
             "
@@ -151,15 +192,52 @@ impl VelesDAO {
         Ok(self.db.last_insert_rowid())
     }
 
+    /// Records that `from_path` was renamed/copied to `to_path` as part of
+    /// `changeset_id`, so history can be followed across the rename.
+    pub fn insert_rename(
+        &self,
+        changeset_id: i64,
+        from_path: &str,
+        to_path: &str,
+    ) -> Result<(), VelesError> {
+        self.db.execute(
+            "INSERT INTO renames (changeset_id, from_path, to_path) VALUES (?1, ?2, ?3)",
+            (changeset_id, from_path, to_path),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the `(changeset_id, from_path)` pairs recording a prior path
+    /// for `path`, most recent first, so callers can walk a file's renames
+    /// back through history.
+    pub fn get_renames_into(&self, path: &str) -> Result<Vec<(i64, String)>, VelesError> {
+        let mut statement = self.db.prepare(
+            "SELECT changeset_id, from_path FROM renames
+             WHERE to_path = ?1
+             ORDER BY changeset_id DESC",
+        )?;
+
+        let rows = statement.query_map([path], |row| {
+            let changeset_id: i64 = row.get(0)?;
+            let from_path: String = row.get(1)?;
+            Ok((changeset_id, from_path))
+        })?;
+
+        let result: Result<Vec<_>, _> = rows.collect();
+        Ok(result?)
+    }
+
     pub fn get_changesets(&self) -> Result<Vec<VelesChange>, VelesError> {
         let mut statement = self
             .db
-            .prepare("SELECT changeset_id, user, description FROM changesets")?;
+            .prepare("SELECT changeset_id, user, description, tree_hash FROM changesets")?;
         let change_iter = statement.query_map([], |row| {
             Ok(VelesChange {
                 id: row.get(0)?,
                 user: row.get(1)?,
                 description: row.get(2)?,
+                tree_hash: row.get(3)?,
             })
         })?;
 
@@ -169,3 +247,51 @@ impl VelesDAO {
         Ok(changesets)
     }
 }
+
+fn user_version(db: &rusqlite::Connection) -> Result<i32, VelesError> {
+    Ok(db.query_row("PRAGMA user_version", [], |row| row.get(0))?)
+}
+
+fn set_user_version(db: &rusqlite::Connection, version: i32) -> Result<(), VelesError> {
+    db.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+
+    Ok(())
+}
+
+/// Brings an existing `.veles/veles.db3` up to [`DB_VERSION`], applying
+/// each pending step in order; a fresh database is created directly at
+/// `DB_VERSION` in [`VelesDAO::new`] instead of running these. Refuses to
+/// open a database newer than this build understands, rather than
+/// misreading its schema.
+fn migrate(db: &rusqlite::Connection) -> Result<(), VelesError> {
+    let mut version = user_version(db)?;
+
+    if version > DB_VERSION {
+        return Err(VelesError::UnsupportedVersion(version as u32));
+    }
+
+    // A database from before this versioning existed has `user_version`
+    // left at SQLite's default of 0 and the original schema, which lacks
+    // the `renames` table. `initialize` can't be rerun over it (its
+    // `CREATE UNIQUE INDEX` fails on an index that already exists), so
+    // this adds just that table and catches the version marker up.
+    if version < 1 {
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS renames (
+                changeset_id INTEGER NOT NULL,
+                from_path TEXT NOT NULL,
+                to_path TEXT NOT NULL,
+                FOREIGN KEY(changeset_id) REFERENCES changesets(changeset_id)
+            )",
+            (),
+        )?;
+        version = 1;
+        set_user_version(db, version)?;
+    }
+
+    // A future schema change adds another `if version < N { ...; version
+    // = N; set_user_version(db, version)?; }` step here, e.g. an `ALTER
+    // TABLE changesets ADD COLUMN ...` for a new column.
+
+    Ok(())
+}
@@ -30,4 +30,22 @@ pub enum VelesError {
 
     #[error("Data not found")]
     NotFound,
+
+    #[error("Cap'n Proto error")]
+    CapnpError(#[from] capnp::Error),
+
+    #[error("repository format version {0} is newer than this build of veles understands")]
+    UnsupportedVersion(u32),
+
+    #[error("repository format version {0} is outdated; run `veles upgrade`")]
+    OutdatedVersion(u32),
+
+    #[error("this store is encrypted; a passphrase is required to open it")]
+    PassphraseRequired,
+
+    #[error("wrong passphrase, or corrupted encrypted row")]
+    WrongPassphrase,
+
+    #[error("encryption error")]
+    CryptoError,
 }
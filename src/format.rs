@@ -0,0 +1,115 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use crate::error::VelesError;
+
+/// The on-disk format version this build of veles understands. Bump this
+/// whenever an incompatible change is made to the object store layout or a
+/// serialized structure under `.veles/`, and add a migration step to
+/// [`upgrade`] for the version being replaced.
+///
+/// Version 3 formalized each log segment's own header (see
+/// [`crate::storage::upgrade_segments`]): a validated magic + version +
+/// flags preamble in place of a single byte that was never checked.
+pub const CURRENT_VERSION: u32 = 3;
+
+/// Version 1 predates this marker entirely: blobs live at `.veles/<hash>`
+/// rather than `.veles/objects/<hash>`, and nothing records a version at
+/// all. A repo with no marker file is assumed to be at this version.
+const LEGACY_VERSION: u32 = 1;
+
+const VERSION_FILE: &str = "format_version";
+
+/// Reads the on-disk format version recorded under `repo_path`, treating a
+/// missing marker as [`LEGACY_VERSION`] rather than an error, since that's
+/// exactly the state of a repo created before this marker existed.
+pub fn read_version(repo_path: &Path) -> Result<u32, VelesError> {
+    match fs::read_to_string(repo_path.join(VERSION_FILE)) {
+        Ok(contents) => contents
+            .trim()
+            .parse()
+            .map_err(|_| VelesError::CorruptedData),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(LEGACY_VERSION),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records `version` as the on-disk format version under `repo_path`.
+pub fn write_version(repo_path: &Path, version: u32) -> Result<(), VelesError> {
+    fs::write(repo_path.join(VERSION_FILE), version.to_string())?;
+    Ok(())
+}
+
+/// Returns an error unless `repo_path` is already at [`CURRENT_VERSION`],
+/// rather than letting a caller misread bytes laid out for a different
+/// version. Callers that can create a brand new repo (with nothing to
+/// migrate) should `write_version(repo_path, CURRENT_VERSION)` themselves
+/// instead of going through this check.
+pub fn require_current(repo_path: &Path) -> Result<(), VelesError> {
+    match read_version(repo_path)? {
+        version if version == CURRENT_VERSION => Ok(()),
+        version if version > CURRENT_VERSION => Err(VelesError::UnsupportedVersion(version)),
+        version => Err(VelesError::OutdatedVersion(version)),
+    }
+}
+
+/// Migrates `repo_path` from its current on-disk version up to
+/// [`CURRENT_VERSION`], returning the version it ended up at. Each step
+/// only runs if the repo is still behind that step, so re-running is safe.
+pub fn upgrade(repo_path: &Path) -> Result<u32, VelesError> {
+    let mut version = read_version(repo_path)?;
+
+    if version < 2 {
+        migrate_legacy_blobs(repo_path)?;
+        version = 2;
+        write_version(repo_path, version)?;
+    }
+
+    if version < 3 {
+        crate::storage::upgrade_segments()?;
+        version = 3;
+        write_version(repo_path, version)?;
+    }
+
+    Ok(version)
+}
+
+/// Relocates blobs stored under the legacy `.veles/<hash>` flat layout into
+/// `.veles/objects/<hash>`, leaving the filename (and thus the blob's
+/// identity) untouched. A blob already present at the destination — from a
+/// previous, interrupted upgrade — is left where it is rather than
+/// overwritten.
+fn migrate_legacy_blobs(repo_path: &Path) -> Result<(), VelesError> {
+    let objects_dir = repo_path.join("objects");
+
+    for entry in fs::read_dir(repo_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        let is_shard = entry.file_type()?.is_dir()
+            && name.len() == 2
+            && name.bytes().all(|b| b.is_ascii_hexdigit());
+        if !is_shard {
+            continue;
+        }
+
+        let dest_dir = objects_dir.join(&name);
+        fs::create_dir_all(&dest_dir)?;
+
+        for blob in fs::read_dir(entry.path())? {
+            let blob = blob?;
+            let dest = dest_dir.join(blob.file_name());
+
+            if !dest.exists() {
+                fs::rename(blob.path(), dest)?;
+            } else {
+                fs::remove_file(blob.path())?;
+            }
+        }
+
+        // The shard directory is now empty (or never held anything but
+        // other directories, which this leaves alone).
+        let _ = fs::remove_dir(entry.path());
+    }
+
+    Ok(())
+}
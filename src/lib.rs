@@ -1,15 +1,16 @@
 use std::{
-    collections::HashMap,
-    ffi::OsString,
-    fs::OpenOptions,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{self, OpenOptions},
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use error::VelesError;
 use flate2::read::GzDecoder;
+use rayon::prelude::*;
 use ring::digest;
 use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
 
 use crate::util::DirIterator;
 
@@ -17,118 +18,222 @@ pub mod client;
 pub mod config;
 pub mod core;
 pub mod error;
+pub mod format;
+pub mod patch;
 pub mod protocol;
 pub mod repo;
 pub mod storage;
 pub mod util;
 
+/// Generated from `schema/veles.capnp` by `build.rs`.
+#[allow(clippy::all)]
+pub mod veles_capnp {
+    include!(concat!(env!("OUT_DIR"), "/veles_capnp.rs"));
+}
+
 pub trait Finalize {
     fn finalize(self) -> Result<String, VelesError>;
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct VelesNode {
-    name: OsString,
-    items: Vec<VelesNode>,
-    hash: Option<String>,
+/// A file's size, modification time, and sniffed content type as of the
+/// changeset that added or renamed it, carried alongside a [`Change`] so it
+/// can be recorded in the resulting tree entry without rereading the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileMeta {
+    pub size: u64,
+    pub mtime: u64,
+    pub content_type: String,
+    pub is_text: bool,
+}
+
+/// A single file-level change being submitted, as detected by
+/// [`client::VelesClient::submit`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Add {
+        path: String,
+        hash: String,
+        meta: FileMeta,
+    },
+    Rename {
+        from: String,
+        to: String,
+        hash: String,
+        meta: FileMeta,
+    },
+}
+
+/// The set of file changes a client submits to a [`repo::VelesRepo`] to
+/// build the next changeset.
+pub struct Changeset {
+    pub owner: String,
+    pub description: String,
+    pub changes: Vec<Change>,
 }
 
+/// Where the [`ManifestCache`] is persisted between `manifest()` runs.
+const MANIFEST_CACHE_PATH: &str = ".veles/manifest_cache";
+const MANIFEST_CACHE_CAPACITY: usize = 4096;
+
+/// A small LRU cache from a directory's `(path, children)` — where
+/// `children` is the same newline-joined "hash name" listing the tree hash
+/// itself is derived from — to its previously computed tree hash, persisted
+/// across `manifest()` runs so an unchanged subtree doesn't need its tree
+/// hash recomputed.
+///
+/// Keying on the directory's own mtime isn't enough: on POSIX a directory's
+/// mtime only changes when an entry is added, removed, or renamed, not when
+/// an existing file's content is edited in place. Keying on the children's
+/// hashes instead means any change that alters what this directory hashes
+/// to — including one that bubbled up from a deeply nested file edit, since
+/// directories are processed deepest-first — always misses the cache.
+#[derive(Default, Serialize, Deserialize)]
+struct ManifestCache {
+    order: VecDeque<(PathBuf, String)>,
+    entries: HashMap<(PathBuf, String), String>,
+}
+
+impl ManifestCache {
+    fn load() -> ManifestCache {
+        fs::read(MANIFEST_CACHE_PATH)
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default()
+    }
+
+    fn get(&mut self, key: &(PathBuf, String)) -> Option<String> {
+        let hash = self.entries.get(key)?.clone();
+
+        self.order.retain(|existing| existing != key);
+        self.order.push_back(key.clone());
+
+        Some(hash)
+    }
+
+    fn insert(&mut self, key: (PathBuf, String), hash: String) {
+        if self.entries.insert(key.clone(), hash).is_none() {
+            self.order.push_back(key);
+
+            if self.order.len() > MANIFEST_CACHE_CAPACITY {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), VelesError> {
+        fs::write(MANIFEST_CACHE_PATH, bincode::serialize(self)?)?;
+        Ok(())
+    }
+}
+
+/// Builds the working tree's manifest: every file's blob hash, and a tree
+/// hash for every directory (including `.`, the root) computed from its
+/// immediate children's names and hashes.
+///
+/// File hashing runs in parallel over all tracked paths. Directory tree
+/// hashes are then computed bottom-up in a single pass, deepest directories
+/// first, rather than repeatedly rescanning for "ready" nodes; a
+/// [`ManifestCache`] lets a directory whose listing is unchanged reuse its
+/// previous tree hash. Every file is still read and hashed on each run.
 pub fn manifest() -> Result<(), VelesError> {
-    let mut nodes = HashMap::new();
+    let mut cache = ManifestCache::load();
+
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    dirs.insert(PathBuf::from("."));
+    let mut files: Vec<PathBuf> = Vec::new();
 
     let iter = DirIterator::from_ignorefile(".", ".velesignore", true)?;
     for path in iter {
         if path.is_dir() {
-            nodes.insert(
-                path.clone(),
-                VelesNode {
-                    name: path.file_name().unwrap().to_owned(),
-                    items: Vec::new(),
-                    hash: None,
-                },
-            );
+            dirs.insert(path);
+        } else {
+            files.push(path);
         }
+    }
 
-        if let Some(parent) = path.parent() {
-            let parent = parent.to_owned();
-            if !nodes.contains_key(&parent) {
-                nodes.insert(
-                    parent.clone(),
-                    VelesNode {
-                        name: parent.file_name().unwrap_or_default().to_owned(),
-                        items: Vec::new(),
-                        hash: None,
-                    },
-                );
-            }
-
-            // let hash = if path.is_file() {
-            //     Some(do_commit(path.clone())?)
-            // } else {
-            //     None
-            // };
-            let hash = None;
-
-            let node = nodes.get_mut(&parent).unwrap();
-            node.items.push(VelesNode {
-                name: path.file_name().unwrap().to_owned(),
-                items: Vec::new(),
-                hash,
-            });
+    let mut children: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for dir in &dirs {
+        if let Some(parent) = dir.parent() {
+            children
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(dir.clone());
+        }
+    }
+    for file in &files {
+        if let Some(parent) = file.parent() {
+            children
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(file.clone());
         }
     }
 
-    println!("Built node tree: {:?}", nodes);
-
-    let mut ref_hash = String::new();
-    let mut hashes: HashMap<OsString, String> = HashMap::new();
-    let mut nodes: Vec<VelesNode> = nodes.into_values().collect();
-    let mut i = 0;
-    while !nodes.is_empty() {
-        let node = &nodes[i];
-
-        let ready = node
-            .items
-            .iter()
-            .all(|n| n.hash.is_some() || hashes.contains_key(&n.name));
-        if ready {
-            let mut contents = String::new();
-
-            for item in &node.items {
-                let item_hash = item
-                    .hash
-                    .as_ref()
-                    .unwrap_or_else(|| hashes.get(&item.name).unwrap());
-                contents.push_str(&format!("{} {}\n", item_hash, item.name.to_string_lossy()));
-            }
+    let file_hashes: HashMap<PathBuf, String> = files
+        .par_iter()
+        .map(|path| -> Result<(PathBuf, String), VelesError> {
+            let content = fs::read(path)?;
 
             let mut context = digest::Context::new(&digest::SHA256);
-            context.update(contents.as_bytes());
-            let digest = context.finish();
-            let hex_digest = hex::encode(digest);
+            context.update(&content);
+
+            Ok((path.clone(), hex::encode(context.finish())))
+        })
+        .collect::<Result<Vec<_>, VelesError>>()?
+        .into_iter()
+        .collect();
+
+    // Deepest directories first, so by the time a directory is processed
+    // every child it depends on (a file, or an already-hashed subdirectory)
+    // is known.
+    let mut dir_list: Vec<PathBuf> = dirs.into_iter().collect();
+    dir_list.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+
+    let mut tree_hashes: HashMap<PathBuf, String> = HashMap::new();
+
+    for dir in &dir_list {
+        // Children (files up front, subdirectories because of the
+        // deepest-first order) are already known by this point, so this
+        // costs nothing beyond what computing the tree hash needs anyway.
+        let mut entries: Vec<(String, String)> = Vec::new();
+        for child in children.get(dir).into_iter().flatten() {
+            let Some(hash) = file_hashes.get(child).or_else(|| tree_hashes.get(child)) else {
+                continue;
+            };
+
+            entries.push((
+                child.file_name().unwrap().to_string_lossy().to_string(),
+                hash.clone(),
+            ));
+        }
+        entries.sort();
 
-            hashes.insert(node.name.clone(), hex_digest[..40].to_owned());
+        let mut contents = String::new();
+        for (name, hash) in &entries {
+            contents.push_str(&format!("{} {}\n", hash, name));
+        }
 
-            println!("Adding tree {}:\n{}", hex_digest[..40].to_owned(), contents);
+        let cache_key = (dir.clone(), contents.clone());
 
-            nodes.remove(i);
+        if let Some(cached) = cache.get(&cache_key) {
+            tree_hashes.insert(dir.clone(), cached);
+            continue;
+        }
 
-            if nodes.is_empty() {
-                ref_hash = hex_digest[..40].to_owned();
-            }
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(contents.as_bytes());
+        let hex_digest = hex::encode(context.finish())[..40].to_string();
 
-            if i == nodes.len() {
-                i = 0;
-            }
-        } else {
-            i += 1;
-
-            if i == nodes.len() {
-                i = 0;
-            }
-        }
+        tree_hashes.insert(dir.clone(), hex_digest.clone());
+        cache.insert(cache_key, hex_digest);
     }
 
+    cache.save()?;
+
+    let ref_hash = tree_hashes.get(Path::new(".")).cloned().unwrap_or_default();
+
     let conn = Connection::open(".veles/veles.db3")?;
 
     conn.execute(
@@ -176,24 +281,47 @@ pub struct VelesChange {
     pub id: u32,
     pub user: String,
     pub description: String,
+    pub tree_hash: String,
 }
 
 pub fn uncommit(hash: String, output: Option<String>) -> Result<(), VelesError> {
-    let path = PathBuf::from(".veles/").join(&hash[..2]);
-    let file_path = path.join(&hash[2..]);
-    let file = OpenOptions::new().read(true).open(file_path)?;
-    let mut decoder = GzDecoder::new(file);
-
-    let mut buf = Vec::new();
-    decoder.read_to_end(&mut buf)?;
+    let repo = repo::VelesRepo::new()?;
+
+    let buf = match repo.read_object(&hash) {
+        Ok(buf) => buf,
+        Err(VelesError::NotFound) => {
+            // Fall back to the legacy single-snapshot blob layout.
+            let path = PathBuf::from(".veles/").join(&hash[..2]);
+            let file_path = path.join(&hash[2..]);
+            let file = OpenOptions::new().read(true).open(file_path)?;
+            let mut decoder = GzDecoder::new(file);
+
+            let mut buf = Vec::new();
+            decoder.read_to_end(&mut buf)?;
+            buf
+        }
+        Err(e) => return Err(e),
+    };
 
     if let Some(out) = output {
         let mut new_file = OpenOptions::new().create(true).write(true).open(out)?;
         new_file.write_all(&buf)?;
-    } else if let Ok(str) = String::from_utf8(buf) {
-        println!("{}", str);
     } else {
-        println!("Failed to parse data as utf-8");
+        let (content_type, is_text) = util::sniff_content_type(&buf);
+
+        if is_text {
+            if let Ok(str) = String::from_utf8(buf) {
+                println!("{}", str);
+            } else {
+                println!("Detected as text ({}) but isn't valid UTF-8.", content_type);
+            }
+        } else {
+            println!(
+                "Binary object ({}, {} bytes); pass -o <file> to write it out.",
+                content_type,
+                buf.len()
+            );
+        }
     }
 
     Ok(())
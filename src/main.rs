@@ -7,14 +7,17 @@ use std::{
 };
 
 use clap::{Parser, Subcommand};
+use config::VelesConfig;
 use error::VelesError;
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use ring::digest;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
-use storage::VelesStore;
+use storage::{Storage, StorageBackend, SyncPolicy};
 
+mod config;
 mod error;
+mod format;
 mod storage;
 
 #[derive(Parser)]
@@ -77,7 +80,17 @@ enum StorageCmd {
         #[arg(short, long)]
         value: String,
     },
+    Delete {
+        #[arg(short, long)]
+        key: String,
+    },
     Compact,
+    /// Bulk-copies every key from the currently configured backend into
+    /// `to`, e.g. to migrate a repo from the log to the sqlite backend.
+    Convert {
+        #[arg(short, long)]
+        to: String,
+    },
 }
 
 fn main() {
@@ -509,20 +522,35 @@ fn status() -> Result<(), VelesError> {
 }
 
 fn storage(command: &StorageCmd) -> Result<(), VelesError> {
-    let mut veles = VelesStore::new()?;
+    let config = VelesConfig::load(&VelesConfig::default_layers(&PathBuf::from(".veles")))?;
+    let mut veles = StorageBackend::from_config(&config).open(None, SyncPolicy::from_config(&config))?;
 
     match command {
         StorageCmd::Get { key } => {
-            let value = veles.get(&key)?;
+            let value = veles.get(key)?;
             println!("{}: {}", key, value);
         }
         StorageCmd::Put { key, value } => {
             veles.put(key, value)?;
             println!("Put {}: {}", key, value);
         }
+        StorageCmd::Delete { key } => {
+            veles.delete(key)?;
+            println!("Deleted {}", key);
+        }
         StorageCmd::Compact => {
             veles.compact()?;
         }
+        StorageCmd::Convert { to } => {
+            let backend = match to.as_str() {
+                "log" => StorageBackend::Log,
+                "sqlite" => StorageBackend::Sqlite,
+                _ => return Err(VelesError::NotFound),
+            };
+            let mut destination = backend.open(None, SyncPolicy::from_config(&config))?;
+            storage::convert(veles.as_ref(), destination.as_mut())?;
+            println!("Converted to {}", to);
+        }
     }
 
     Ok(())
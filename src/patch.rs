@@ -0,0 +1,382 @@
+//! A patch-based history representation, in the spirit of Pijul.
+//!
+//! Instead of hashing whole trees, a file is modeled as a graph of line
+//! *vertices* with stable, content-derived identities, and a change is a
+//! set of edge insertions/deletions over those identities. Two patches that
+//! don't touch the same vertices commute: they can be applied in either
+//! order and produce the same graph. Merging two branches is just the union
+//! of their patches, applied in any order that respects dependencies.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+
+/// The stable identity of a single line of a file. Derived from the
+/// content of the line plus the id of the patch that introduced it, so two
+/// identical lines introduced by different patches get distinct vertices.
+pub type VertexId = String;
+
+/// A start-of-file sentinel every patch's first real vertex depends on.
+pub const ROOT: &str = "root";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vertex {
+    pub id: VertexId,
+    pub line: String,
+}
+
+/// An edge a patch contributes to the graph: either a new vertex inserted
+/// directly after `after`, or an existing vertex marked dead.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Edge {
+    Insert { after: VertexId, vertex: Vertex },
+    Delete { vertex: VertexId },
+}
+
+/// A single unit of change: a set of edges plus the vertex ids it assumes
+/// already exist in the graph (its dependencies).
+///
+/// `id` is assigned once, up front, when the patch is created — not derived
+/// from its content — so that it can in turn be mixed into the vertex ids
+/// of any line the patch inserts (see [`VertexId`]). This is also why two
+/// patches with identical dependencies and edges (e.g. the same edit made
+/// independently on two branches) are still distinct patches with distinct
+/// ids, rather than collapsing into one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Patch {
+    pub id: String,
+    pub dependencies: Vec<VertexId>,
+    pub edges: Vec<Edge>,
+}
+
+impl Patch {
+    /// Builds a patch from a diff between `before` and `after`, where each
+    /// inserted line gets a freshly derived vertex id — mixing in this
+    /// patch's own freshly assigned id, so the same line inserted by a
+    /// different patch never collides with it — and each surviving line
+    /// keeps the vertex id it was read in with.
+    pub fn diff(before: &[Vertex], after: &[&str]) -> Patch {
+        let id = generate_patch_id();
+
+        let before_lines: Vec<&str> = before.iter().map(|v| v.line.as_str()).collect();
+        let ops = line_diff(&before_lines, after);
+
+        let mut edges = Vec::new();
+        let mut dependencies = Vec::new();
+        let mut previous = ROOT.to_string();
+
+        for op in ops {
+            match op {
+                DiffOp::Keep(idx) => {
+                    let vertex = &before[idx];
+                    dependencies.push(vertex.id.clone());
+                    previous = vertex.id.clone();
+                }
+                DiffOp::Delete(idx) => {
+                    let vertex = &before[idx];
+                    dependencies.push(vertex.id.clone());
+                    edges.push(Edge::Delete {
+                        vertex: vertex.id.clone(),
+                    });
+                }
+                DiffOp::Insert(line) => {
+                    dependencies.push(previous.clone());
+                    let vertex_id = derive_vertex_id(&id, &previous, line);
+                    let vertex = Vertex {
+                        id: vertex_id.clone(),
+                        line: line.to_string(),
+                    };
+                    edges.push(Edge::Insert {
+                        after: previous.clone(),
+                        vertex,
+                    });
+                    previous = vertex_id;
+                }
+            }
+        }
+
+        dependencies.sort();
+        dependencies.dedup();
+
+        Patch {
+            id,
+            dependencies,
+            edges,
+        }
+    }
+
+    /// A patch introduces a vertex if it has an `Insert` edge for it.
+    fn introduced(&self) -> HashSet<&VertexId> {
+        self.edges
+            .iter()
+            .filter_map(|e| match e {
+                Edge::Insert { vertex, .. } => Some(&vertex.id),
+                Edge::Delete { .. } => None,
+            })
+            .collect()
+    }
+
+    /// A patch removes a vertex if it has a `Delete` edge for it.
+    fn removed(&self) -> HashSet<&VertexId> {
+        self.edges
+            .iter()
+            .filter_map(|e| match e {
+                Edge::Delete { vertex } => Some(vertex),
+                Edge::Insert { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Two patches commute when applying them in either order leaves the
+    /// graph in the same state: neither may depend on a vertex the other
+    /// introduces or removes.
+    pub fn commutes_with(&self, other: &Patch) -> bool {
+        let other_touched: HashSet<&VertexId> =
+            other.introduced().union(&other.removed()).cloned().collect();
+        let self_touched: HashSet<&VertexId> =
+            self.introduced().union(&self.removed()).cloned().collect();
+
+        let self_depends_on_other = self.dependencies.iter().any(|d| other_touched.contains(d));
+        let other_depends_on_self = other.dependencies.iter().any(|d| self_touched.contains(d));
+
+        !self_depends_on_other && !other_depends_on_self
+    }
+}
+
+/// Whether `a` and `b` commute (can be applied in either order).
+pub fn commute(a: &Patch, b: &Patch) -> bool {
+    a.commutes_with(b)
+}
+
+/// A region of the graph where two or more live vertices have no ordering
+/// edge between them, so their relative order is ambiguous and must be
+/// resolved by hand.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub vertices: Vec<VertexId>,
+}
+
+/// The materialized graph of applied patches: every vertex seen so far,
+/// which ones are still alive, and the insertion order edges between them.
+#[derive(Default)]
+pub struct PatchGraph {
+    vertices: HashMap<VertexId, Vertex>,
+    alive: HashSet<VertexId>,
+    successors: HashMap<VertexId, HashSet<VertexId>>,
+    applied: HashSet<String>,
+}
+
+impl PatchGraph {
+    pub fn new() -> PatchGraph {
+        PatchGraph::default()
+    }
+
+    /// Applies a single patch to the graph. Applying the same patch twice
+    /// is a no-op, so merges don't need to dedupe their patch sets first.
+    pub fn apply(&mut self, patch: &Patch) {
+        if !self.applied.insert(patch.id.clone()) {
+            return;
+        }
+
+        for edge in &patch.edges {
+            match edge {
+                Edge::Insert { after, vertex } => {
+                    self.vertices.insert(vertex.id.clone(), vertex.clone());
+                    self.alive.insert(vertex.id.clone());
+                    self.successors
+                        .entry(after.clone())
+                        .or_default()
+                        .insert(vertex.id.clone());
+                }
+                Edge::Delete { vertex } => {
+                    self.alive.remove(vertex);
+                }
+            }
+        }
+    }
+
+    /// Merges two branches' patch sets: applies every patch from both in
+    /// any order that respects dependencies (a patch is applied once all
+    /// the vertices it depends on exist). Patches common to both branches
+    /// are only applied once.
+    ///
+    /// Returns the merged graph plus any patches that were never applied
+    /// because a dependency of theirs never became available from `a ∪ b`
+    /// (e.g. a patch depends on a vertex introduced by a patch missing from
+    /// both slices) — the caller decides whether that's an error, a
+    /// conflict to surface to the user, or safe to ignore, rather than
+    /// those patches silently vanishing from history.
+    pub fn merge(a: &[Patch], b: &[Patch]) -> (PatchGraph, Vec<Patch>) {
+        let mut graph = PatchGraph::new();
+
+        let mut pending: Vec<Patch> = a.iter().chain(b.iter()).cloned().collect();
+        let mut made_progress = true;
+
+        while made_progress && !pending.is_empty() {
+            made_progress = false;
+
+            let mut remaining = Vec::new();
+            for patch in pending {
+                let ready = patch
+                    .dependencies
+                    .iter()
+                    .all(|dep| dep == ROOT || graph.vertices.contains_key(dep));
+
+                if ready {
+                    graph.apply(&patch);
+                    made_progress = true;
+                } else {
+                    remaining.push(patch);
+                }
+            }
+
+            pending = remaining;
+        }
+
+        (graph, pending)
+    }
+
+    /// Walks the graph in dependency order, grouping vertices that become
+    /// ready at the same time with no ordering edge between them into a
+    /// [`Conflict`], and returns the flattened lines plus any conflicts
+    /// found along the way.
+    pub fn materialize(&self) -> (Vec<String>, Vec<Conflict>) {
+        let mut in_degree: HashMap<&VertexId, usize> =
+            self.alive.iter().map(|id| (id, 0)).collect();
+
+        for (from, tos) in &self.successors {
+            if from == ROOT {
+                continue;
+            }
+            for to in tos {
+                if self.alive.contains(to) {
+                    *in_degree.entry(to).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut frontier: VecDeque<&VertexId> = self
+            .alive
+            .iter()
+            .filter(|id| *in_degree.get(id).unwrap_or(&0) == 0)
+            .collect();
+
+        let mut lines = Vec::new();
+        let mut conflicts = Vec::new();
+        let mut visited = HashSet::new();
+
+        while !frontier.is_empty() {
+            let mut ready: Vec<&VertexId> = frontier.drain(..).collect();
+            ready.sort();
+
+            if ready.len() > 1 {
+                conflicts.push(Conflict {
+                    vertices: ready.iter().map(|id| (*id).clone()).collect(),
+                });
+            }
+
+            for id in ready {
+                if !visited.insert(id.clone()) {
+                    continue;
+                }
+
+                if let Some(vertex) = self.vertices.get(id) {
+                    lines.push(vertex.line.clone());
+                }
+
+                if let Some(successors) = self.successors.get(id) {
+                    for next in successors {
+                        if !self.alive.contains(next) {
+                            continue;
+                        }
+                        let degree = in_degree.entry(next).or_insert(0);
+                        *degree = degree.saturating_sub(1);
+                        if *degree == 0 {
+                            frontier.push_back(next);
+                        }
+                    }
+                }
+            }
+        }
+
+        (lines, conflicts)
+    }
+}
+
+enum DiffOp<'a> {
+    Keep(usize),
+    Delete(usize),
+    Insert(&'a str),
+}
+
+/// A minimal Myers-style line diff: longest common subsequence via DP,
+/// producing keep/delete/insert operations.
+fn line_diff<'a>(before: &[&str], after: &[&'a str]) -> Vec<DiffOp<'a>> {
+    let n = before.len();
+    let m = after.len();
+
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if before[i] == after[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < n && j < m {
+        if before[i] == after[j] {
+            ops.push(DiffOp::Keep(i));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            ops.push(DiffOp::Delete(i));
+            i += 1;
+        } else {
+            ops.push(DiffOp::Insert(after[j]));
+            j += 1;
+        }
+    }
+
+    while i < n {
+        ops.push(DiffOp::Delete(i));
+        i += 1;
+    }
+
+    while j < m {
+        ops.push(DiffOp::Insert(after[j]));
+        j += 1;
+    }
+
+    ops
+}
+
+/// Assigns a fresh, random id to a newly created patch (see the note on
+/// [`Patch`]), rather than deriving one from content that isn't fully known
+/// until the diff below finishes building it.
+fn generate_patch_id() -> String {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("system RNG should not fail");
+    hex::encode(bytes)
+}
+
+fn derive_vertex_id(patch_id: &str, previous: &str, line: &str) -> VertexId {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(patch_id.as_bytes());
+    context.update(b"\0");
+    context.update(previous.as_bytes());
+    context.update(b"\0");
+    context.update(line.as_bytes());
+    hex::encode(context.finish())
+}
@@ -1,8 +1,22 @@
-use std::io::Write;
+use std::{
+    cell::RefCell,
+    io::Write,
+    net::{TcpListener, TcpStream},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use capnp::message::ReaderOptions;
 use serde::{Deserialize, Serialize};
 
-use crate::{error::VelesError, repo::VelesRepo, Finalize, VelesChange};
+use crate::{
+    error::VelesError,
+    repo::{CatResult, FsckIssue, TreeEntry, VelesRepo},
+    util::detect_content_type,
+    veles_capnp::request,
+    veles_capnp::response,
+    Change, Changeset, FileMeta, Finalize, VelesChange,
+};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct VelesMessage {
@@ -35,13 +49,40 @@ impl LocalTransport {
         self.repo.changesets()
     }
 
-    pub fn submit(&self, user: &str, description: &str) -> Result<(), VelesError> {
-        self.repo.submit(user, description)
+    pub fn submit(&self, changeset: &Changeset) -> Result<i64, VelesError> {
+        self.repo.submit(changeset)
     }
 
     pub fn send_object(&self) -> Result<impl Write + Finalize, VelesError> {
         self.repo.new_object()
     }
+
+    /// Stores `content` as the next revision of `path` in the chunked
+    /// object store (see [`VelesRepo::write_revision`]).
+    pub fn send_revision(&self, path: &str, content: &[u8]) -> Result<String, VelesError> {
+        self.repo.write_revision(path, content)
+    }
+
+    /// Returns the current `path -> tree entry` tree for the main task.
+    pub fn read_tree(&self) -> Result<std::collections::HashMap<String, TreeEntry>, VelesError> {
+        self.repo.read_tree()
+    }
+
+    pub fn read_object(&self, hash: &str) -> Result<Vec<u8>, VelesError> {
+        self.repo.read_object(hash)
+    }
+
+    pub fn cat(&self, revision: &str, paths: &[String]) -> Result<CatResult, VelesError> {
+        self.repo.cat(revision, paths)
+    }
+
+    pub fn files(&self, revision: &str) -> Result<Vec<String>, VelesError> {
+        self.repo.files(revision)
+    }
+
+    pub fn fsck(&self) -> Result<Vec<FsckIssue>, VelesError> {
+        self.repo.fsck()
+    }
 }
 
 impl VelesProtocol for LocalTransport {
@@ -49,3 +90,233 @@ impl VelesProtocol for LocalTransport {
         Ok(())
     }
 }
+
+/// A [`VelesProtocol`] that talks to a `veles server` over TCP using the
+/// Cap'n Proto schema in `schema/veles.capnp`. A submit is a `submitStart`,
+/// one `fileWrite` per added file, then a `submitFinalize` that returns the
+/// new changeset id. Requests go through its own methods; its
+/// [`VelesProtocol::send_message`] sends nothing.
+pub struct RemoteTransport {
+    stream: RefCell<TcpStream>,
+}
+
+impl RemoteTransport {
+    pub fn connect(addr: &str) -> Result<RemoteTransport, VelesError> {
+        let stream = TcpStream::connect(addr)?;
+
+        Ok(RemoteTransport {
+            stream: RefCell::new(stream),
+        })
+    }
+
+    fn call(
+        &self,
+        message: &capnp::message::Builder<capnp::message::HeapAllocator>,
+    ) -> Result<capnp::message::Reader<capnp::serialize::OwnedSegments>, VelesError> {
+        let mut stream = self.stream.borrow_mut();
+        capnp::serialize::write_message(&mut *stream, message)?;
+
+        let reader = capnp::serialize::read_message(&mut *stream, ReaderOptions::new())?;
+        Ok(reader)
+    }
+
+    pub fn submit_start(&self, owner: &str, description: &str) -> Result<(), VelesError> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut req = message.init_root::<request::Builder>();
+            let mut submit_start = req.init_submit_start();
+            submit_start.set_owner(owner);
+            submit_start.set_description(description);
+        }
+
+        self.call(&message)?;
+        Ok(())
+    }
+
+    pub fn file_write(&self, path: &str, data: &[u8]) -> Result<(), VelesError> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut req = message.init_root::<request::Builder>();
+            let mut file_write = req.init_file_write();
+            file_write.set_path(path);
+            file_write.set_data(data);
+        }
+
+        self.call(&message)?;
+        Ok(())
+    }
+
+    pub fn submit_finalize(&self) -> Result<i64, VelesError> {
+        let mut message = capnp::message::Builder::new_default();
+        message
+            .init_root::<request::Builder>()
+            .init_submit_finalize();
+
+        let reader = self.call(&message)?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::ChangesetId(id) => Ok(id),
+            response::Error(err) => Err(VelesError::CapnpError(capnp::Error::failed(
+                err?.to_string()?,
+            ))),
+            _ => Err(VelesError::CapnpError(capnp::Error::failed(
+                "unexpected response to submitFinalize".to_string(),
+            ))),
+        }
+    }
+
+    pub fn list_changesets(&self) -> Result<Vec<VelesChange>, VelesError> {
+        let mut message = capnp::message::Builder::new_default();
+        message
+            .init_root::<request::Builder>()
+            .set_list_changesets(());
+
+        let reader = self.call(&message)?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::Changesets(list) => {
+                let list = list?;
+                let mut result = Vec::new();
+
+                for entry in list.get_changesets()?.iter() {
+                    result.push(VelesChange {
+                        id: entry.get_id() as u32,
+                        user: entry.get_user()?.to_string()?,
+                        description: entry.get_description()?.to_string()?,
+                        tree_hash: entry.get_tree_hash()?.to_string()?,
+                    });
+                }
+
+                Ok(result)
+            }
+            _ => Err(VelesError::CapnpError(capnp::Error::failed(
+                "unexpected response to listChangesets".to_string(),
+            ))),
+        }
+    }
+}
+
+impl VelesProtocol for RemoteTransport {
+    fn send_message(&self, _message: &VelesMessage) -> Result<(), VelesError> {
+        Ok(())
+    }
+}
+
+/// Runs a `veles server` that accepts connections on `addr`, persisting
+/// submitted objects and changesets through a [`VelesRepo`]. Connections are
+/// served one at a time; each answers any number of `listChangesets` and
+/// `fetchObject` requests and at most one submit sequence (`submitStart`,
+/// zero or more `fileWrite`s, and a `submitFinalize`), and is closed after
+/// that `submitFinalize`.
+pub fn serve(addr: &str) -> Result<(), VelesError> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!("veles server listening on {}", addr);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        if let Err(e) = handle_connection(stream) {
+            log::error!("connection error: {:?}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream) -> Result<(), VelesError> {
+    let repo = VelesRepo::new()?;
+
+    let mut owner = String::new();
+    let mut description = String::new();
+    let mut changes: Vec<Change> = Vec::new();
+
+    loop {
+        let reader = capnp::serialize::read_message(&mut stream, ReaderOptions::new())?;
+        let req = reader.get_root::<request::Reader>()?;
+
+        let mut response = capnp::message::Builder::new_default();
+
+        match req.which()? {
+            request::SubmitStart(start) => {
+                let start = start?;
+                owner = start.get_owner()?.to_string()?;
+                description = start.get_description()?.to_string()?;
+                response.init_root::<response::Builder>().set_ok(());
+            }
+            request::FileWrite(write) => {
+                let write = write?;
+                let path = write.get_path()?.to_string()?;
+                let data = write.get_data()?;
+                let hash = repo.write_revision(&path, data)?;
+                let meta = received_file_meta(&path, data);
+                changes.push(Change::Add { path, hash, meta });
+                response.init_root::<response::Builder>().set_ok(());
+            }
+            request::SubmitFinalize(()) => {
+                let changeset = Changeset {
+                    owner: owner.clone(),
+                    description: description.clone(),
+                    changes: changes.clone(),
+                };
+                let changeset_id = repo.submit(&changeset)?;
+                response
+                    .init_root::<response::Builder>()
+                    .set_changeset_id(changeset_id);
+
+                capnp::serialize::write_message(&mut stream, &response)?;
+                return Ok(());
+            }
+            request::ListChangesets(()) => {
+                let changesets = repo.changesets()?;
+                let mut list = response
+                    .init_root::<response::Builder>()
+                    .init_changesets()
+                    .init_changesets(changesets.len() as u32);
+
+                for (i, changeset) in changesets.iter().enumerate() {
+                    let mut entry = list.reborrow().get(i as u32);
+                    entry.set_id(changeset.id as i64);
+                    entry.set_user(&changeset.user);
+                    entry.set_description(&changeset.description);
+                    entry.set_tree_hash(&changeset.tree_hash);
+                }
+            }
+            request::FetchObject(fetch) => {
+                let hash = fetch?.get_hash()?.to_string()?;
+                let mut resp = response.init_root::<response::Builder>().init_object();
+
+                match repo.read_object(&hash) {
+                    Ok(data) => {
+                        resp.set_found(true);
+                        resp.set_data(&data);
+                    }
+                    Err(VelesError::NotFound) => resp.set_found(false),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        capnp::serialize::write_message(&mut stream, &response)?;
+    }
+}
+
+/// Builds the [`FileMeta`] recorded for a file received over `fileWrite`.
+/// There's no local file on the server's disk to stat, so `mtime` is the
+/// time the write was received rather than a modification time; `size` and
+/// the sniffed content type come from `data` itself, same as the local
+/// submit path in `client.rs`.
+fn received_file_meta(path: &str, data: &[u8]) -> FileMeta {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (content_type, is_text) = detect_content_type(Path::new(path), data);
+
+    FileMeta {
+        size: data.len() as u64,
+        mtime,
+        content_type,
+        is_text,
+    }
+}
@@ -1,17 +1,18 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, OpenOptions},
-    io::Write,
+    io::{Read, Write},
     path::PathBuf,
 };
 
-use flate2::{write, Compression};
+use flate2::{read::GzDecoder, write, Compression};
 use ring::digest;
 
 use crate::{
     dao::{self, VelesDAO},
     error::VelesError,
-    Changeset, Finalize, VelesChange,
+    storage::{ChunkStore, Revlog},
+    Change, Changeset, FileMeta, Finalize, VelesChange,
 };
 
 pub struct Object {
@@ -68,12 +69,98 @@ impl Finalize for Object {
     }
 }
 
+/// The result of [`VelesRepo::cat`]: the paths that were found at the
+/// requested revision (with their content, in request order) and the ones
+/// that weren't tracked there.
+pub struct CatResult {
+    pub found: Vec<(String, Vec<u8>)>,
+    pub missing: Vec<String>,
+}
+
+/// One file's entry in a tree object: its content hash, plus the
+/// size/mtime/content-type metadata recorded when it was added or renamed.
+/// The metadata fields are `None` for a tree line written before this
+/// metadata existed, so older trees keep decoding.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub hash: String,
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    pub content_type: Option<String>,
+    pub is_text: Option<bool>,
+}
+
+impl TreeEntry {
+    fn new(hash: String, meta: &FileMeta) -> TreeEntry {
+        TreeEntry {
+            hash,
+            size: Some(meta.size),
+            mtime: Some(meta.mtime),
+            content_type: Some(meta.content_type.clone()),
+            is_text: Some(meta.is_text),
+        }
+    }
+
+    /// Formats this entry as one `<path> <hash> [<size> <mtime>
+    /// <content_type> <is_text>]` tree line.
+    fn to_line(&self, path: &str) -> String {
+        match (&self.size, &self.mtime, &self.content_type, &self.is_text) {
+            (Some(size), Some(mtime), Some(content_type), Some(is_text)) => format!(
+                "{} {} {} {} {} {}\n",
+                path,
+                self.hash,
+                size,
+                mtime,
+                content_type,
+                *is_text as u8
+            ),
+            _ => format!("{} {}\n", path, self.hash),
+        }
+    }
+}
+
+fn read_tree_object(tree_hash: &str) -> Result<HashMap<String, TreeEntry>, VelesError> {
+    let path = PathBuf::from(".veles/objects/")
+        .join(&tree_hash[..2])
+        .join(&tree_hash[2..40]);
+    let tree_contents = fs::read_to_string(&path)?;
+
+    let mut tree = HashMap::new();
+    for line in tree_contents.lines() {
+        let mut fields = line.split_whitespace();
+
+        let (Some(file), Some(hash)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+
+        let size = fields.next().and_then(|field| field.parse().ok());
+        let mtime = fields.next().and_then(|field| field.parse().ok());
+        let content_type = fields.next().map(str::to_string);
+        let is_text = fields.next().map(|field| field == "1");
+
+        tree.insert(
+            file.to_string(),
+            TreeEntry {
+                hash: hash.to_string(),
+                size,
+                mtime,
+                content_type,
+                is_text,
+            },
+        );
+    }
+
+    Ok(tree)
+}
+
 pub struct VelesRepo {
     dao: VelesDAO,
 }
 
 impl VelesRepo {
     pub fn new() -> Result<VelesRepo, VelesError> {
+        crate::format::require_current(&PathBuf::from(".veles"))?;
+
         let dao = VelesDAO::new()?;
 
         // Guarantee the main task always exists.
@@ -88,36 +175,97 @@ impl VelesRepo {
         Ok(())
     }
 
+    /// Returns the current `path -> tree entry` tree for the main task, if
+    /// any changesets have been submitted yet.
+    pub fn read_tree(&self) -> Result<HashMap<String, TreeEntry>, VelesError> {
+        self.resolve_tree("main")
+    }
+
+    /// Resolves `revision` (either `"main"` for the latest changeset, or a
+    /// changeset id) to its `path -> tree entry` tree.
+    pub fn resolve_tree(&self, revision: &str) -> Result<HashMap<String, TreeEntry>, VelesError> {
+        let tree_hash = if revision == "main" {
+            let task = self.dao.get_task("main")?;
+            match self.dao.get_latest_changeset(task.task_id)? {
+                Some(latest) => latest.tree_hash,
+                None => return Ok(HashMap::new()),
+            }
+        } else {
+            let changeset_id: i32 = revision.parse().map_err(|_| VelesError::NotFound)?;
+            self.dao.get_changeset(changeset_id)?.tree_hash
+        };
+
+        read_tree_object(&tree_hash)
+    }
+
+    /// Reconstructs the content of `paths` at `revision`, looking each one
+    /// up in the revision's tree and reading it back out of the object
+    /// store. Reports which of the requested paths weren't tracked at that
+    /// revision rather than failing outright, so callers can concatenate
+    /// whichever paths did match.
+    pub fn cat(&self, revision: &str, paths: &[String]) -> Result<CatResult, VelesError> {
+        let tree = self.resolve_tree(revision)?;
+
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+
+        for path in paths {
+            match tree.get(path) {
+                Some(entry) => found.push((path.clone(), self.read_object(&entry.hash)?)),
+                None => missing.push(path.clone()),
+            }
+        }
+
+        Ok(CatResult { found, missing })
+    }
+
+    /// Lists every path tracked at `revision`, in manifest (sorted) order.
+    pub fn files(&self, revision: &str) -> Result<Vec<String>, VelesError> {
+        let mut paths: Vec<String> = self.resolve_tree(revision)?.into_keys().collect();
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// Stores `content` as the next revision of `_path`, chunked with
+    /// [`ChunkStore`] so that unchanged regions are only ever stored once,
+    /// even across different files. `_path` doesn't factor into storage
+    /// itself, since chunking already gets cross-revision dedup without
+    /// needing to know a file's prior revision.
+    pub fn write_revision(&self, _path: &str, content: &[u8]) -> Result<String, VelesError> {
+        ChunkStore::write(content)
+    }
+
     pub fn submit(&self, changeset: &Changeset) -> Result<i64, VelesError> {
         // Get the contextual information we need.
         let task = self.dao.get_task("main")?;
         let latest = self.dao.get_latest_changeset(task.task_id)?;
         let previous_changeset = latest.as_ref().map_or(0, |latest| latest.changeset_id);
 
-        let mut tree = HashMap::new();
-
-        // Read in the tree data if it exists.
-        if let Some(latest) = latest {
-            let path = PathBuf::from(".veles/objects/")
-                .join(&latest.tree_hash[..2])
-                .join(&latest.tree_hash[2..40]);
-            let tree_contents = fs::read_to_string(&path)?;
-
-            for line in tree_contents.lines() {
-                let (file, hash) = line.split_at(line.find(' ').unwrap());
-                tree.insert(file.to_string(), hash.to_string());
-            }
-        }
+        let mut tree = self.read_tree()?;
 
         // Now add in the changeset data.
-        for (file, hash) in &changeset.changes {
-            tree.insert(file.to_string(), hash.to_string());
+        for change in &changeset.changes {
+            match change {
+                Change::Add { path, hash, meta } => {
+                    tree.insert(path.clone(), TreeEntry::new(hash.clone(), meta));
+                }
+                Change::Rename {
+                    from,
+                    to,
+                    hash,
+                    meta,
+                } => {
+                    tree.remove(from);
+                    tree.insert(to.clone(), TreeEntry::new(hash.clone(), meta));
+                }
+            }
         }
 
         // Create the tree file.
         let mut tree_contents = String::new();
-        for (file, hash) in tree {
-            tree_contents.push_str(&format!("{} {}\n", file, hash));
+        for (file, entry) in tree {
+            tree_contents.push_str(&entry.to_line(&file));
         }
 
         let mut obj_writer = Object::new()?;
@@ -125,14 +273,22 @@ impl VelesRepo {
         let tree_hash = obj_writer.finalize()?;
 
         // Finally build the new changeset and submit it.
-        self.dao.insert_changeset(&dao::Changeset {
+        let changeset_id = self.dao.insert_changeset(&dao::Changeset {
             changeset_id: -1,
             previous_changeset,
             task_id: task.task_id,
             user: changeset.owner.clone(),
             description: changeset.description.clone(),
             tree_hash,
-        })
+        })?;
+
+        for change in &changeset.changes {
+            if let Change::Rename { from, to, .. } = change {
+                self.dao.insert_rename(changeset_id, from, to)?;
+            }
+        }
+
+        Ok(changeset_id)
     }
 
     pub fn changesets(&self) -> Result<Vec<VelesChange>, VelesError> {
@@ -142,4 +298,173 @@ impl VelesRepo {
     pub fn new_object(&self) -> Result<Object, VelesError> {
         Object::new()
     }
+
+    /// Reconstructs a stored file revision. Revisions written by the current
+    /// [`ChunkStore`]-based `write_revision` are read directly; revisions
+    /// written by the older per-file revlog scheme are found by scanning
+    /// the revlogs for the one holding `hash`, since that scheme doesn't let
+    /// the hash alone identify which revlog to open.
+    pub fn read_object(&self, hash: &str) -> Result<Vec<u8>, VelesError> {
+        match ChunkStore::read(hash) {
+            Ok(content) => return Ok(content),
+            Err(VelesError::NotFound) | Err(VelesError::IOError(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let dir = PathBuf::from(".veles/objects/revlog");
+        if !dir.exists() {
+            return Err(VelesError::NotFound);
+        }
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if path.extension().and_then(|ext| ext.to_str()) != Some("idx") {
+                continue;
+            }
+
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let revlog = Revlog::open(&name)?;
+
+            if let Ok(content) = revlog.read_revision(hash) {
+                return Ok(content);
+            }
+        }
+
+        Err(VelesError::NotFound)
+    }
+
+    /// Verifies the object store against the changeset history. Checks
+    /// three things: every object decompresses and, unless it's a chunk
+    /// manifest, hashes back to its filename (otherwise [`FsckIssue::Corrupt`]);
+    /// every hash a changeset's tree or a chunk manifest references exists
+    /// on disk (otherwise [`FsckIssue::Missing`]); and every object on disk
+    /// is reachable from some changeset (otherwise [`FsckIssue::Orphaned`],
+    /// which is reclaimable but not itself corruption).
+    pub fn fsck(&self) -> Result<Vec<FsckIssue>, VelesError> {
+        let mut issues = Vec::new();
+        let mut reachable = HashSet::new();
+
+        for changeset in self.dao.get_changesets()? {
+            let tree_hash = self.dao.get_changeset(changeset.id as i32)?.tree_hash;
+
+            let tree = match read_tree_object(&tree_hash) {
+                Ok(tree) => tree,
+                Err(_) => {
+                    issues.push(FsckIssue::Missing(tree_hash));
+                    continue;
+                }
+            };
+
+            reachable.insert(tree_hash);
+            for entry in tree.values() {
+                walk_reachable(self, &entry.hash, &mut reachable, &mut issues);
+            }
+        }
+
+        // `all_hashes` names objects by their on-disk path (the first 40
+        // hex digits), while `reachable` holds full hashes.
+        let reachable_objects: HashSet<String> = reachable
+            .iter()
+            .map(|hash| hash[..hash.len().min(40)].to_string())
+            .collect();
+
+        for hash in ChunkStore::all_hashes()? {
+            if !ChunkStore::verify(&hash)? {
+                issues.push(FsckIssue::Corrupt(hash.clone()));
+            }
+
+            if !reachable_objects.contains(&hash[..hash.len().min(40)]) {
+                issues.push(FsckIssue::Orphaned(hash));
+            }
+        }
+
+        for hash in legacy_blob_hashes()? {
+            match fs::File::open(legacy_blob_path(&hash)) {
+                Ok(file) => {
+                    let mut decoder = GzDecoder::new(file);
+                    let mut buf = Vec::new();
+
+                    let valid = decoder.read_to_end(&mut buf).is_ok()
+                        && hex::encode(digest::digest(&digest::SHA256, &buf)) == hash;
+
+                    if !valid {
+                        issues.push(FsckIssue::Corrupt(hash));
+                    }
+                }
+                Err(_) => issues.push(FsckIssue::Missing(hash)),
+            }
+        }
+
+        Ok(issues)
+    }
+}
+
+/// Marks `hash` reachable and, if it's a chunk manifest, recurses into the
+/// chunks it names, flagging a [`FsckIssue::Missing`] for any that aren't on
+/// disk. Already-visited hashes are skipped, both to avoid repeated disk
+/// reads when many files share a chunk and to guarantee termination.
+///
+/// A hash absent from the `ChunkStore` may still be one written by the
+/// older per-file revlog backend, so existence is checked the same way
+/// [`VelesRepo::read_object`] does, rather than through `ChunkStore` alone
+/// — otherwise every changeset still referencing revlog-stored hashes would
+/// be reported as missing.
+fn walk_reachable(repo: &VelesRepo, hash: &str, reachable: &mut HashSet<String>, issues: &mut Vec<FsckIssue>) {
+    if !reachable.insert(hash.to_string()) {
+        return;
+    }
+
+    if ChunkStore::exists(hash) {
+        if let Ok(Some(chunk_hashes)) = ChunkStore::manifest_chunks(hash) {
+            for chunk_hash in chunk_hashes {
+                walk_reachable(repo, &chunk_hash, reachable, issues);
+            }
+        }
+        return;
+    }
+
+    if repo.read_object(hash).is_err() {
+        issues.push(FsckIssue::Missing(hash.to_string()));
+    }
+}
+
+/// Lists the hashes present under the legacy `.veles/<hash>` flat blob
+/// layout that predates the object store.
+fn legacy_blob_hashes() -> Result<Vec<String>, VelesError> {
+    let mut hashes = Vec::new();
+
+    for entry in fs::read_dir(".veles")? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if !entry.file_type()?.is_dir() || name.len() != 2 || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        for blob in fs::read_dir(entry.path())? {
+            let blob = blob?;
+            hashes.push(format!("{}{}", name, blob.file_name().to_string_lossy()));
+        }
+    }
+
+    Ok(hashes)
+}
+
+fn legacy_blob_path(hash: &str) -> PathBuf {
+    PathBuf::from(".veles/").join(&hash[..2]).join(&hash[2..])
+}
+
+/// A single problem found by [`VelesRepo::fsck`].
+#[derive(Debug)]
+pub enum FsckIssue {
+    /// An object failed to decompress, or its content doesn't hash back to
+    /// its filename.
+    Corrupt(String),
+    /// A changeset's tree, or a chunk manifest, references a hash that
+    /// isn't present in the object store.
+    Missing(String),
+    /// An object on disk isn't reachable from any changeset.
+    Orphaned(String),
 }
@@ -1,16 +1,123 @@
 use std::{
     collections::HashMap,
     fs::{self, File, OpenOptions},
-    io::{Read, Seek, Write},
-    path::PathBuf,
+    io::{BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use argon2::Argon2;
 use crc32fast::Hasher;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+use rusqlite::{named_params, Connection};
 use serde::{Deserialize, Serialize};
 
 use crate::error::VelesError;
 
+/// Which AEAD, if any, a row (or the store as a whole) is sealed with. `0`
+/// (plaintext) is always valid so an unencrypted log, and any row written
+/// before encryption was turned on, keep decoding.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum EncryptionType {
+    None = 0,
+    AesGcm = 1,
+    ChaCha20Poly1305 = 2,
+}
+
+impl EncryptionType {
+    fn from_i16(value: i16) -> Result<EncryptionType, VelesError> {
+        match value {
+            0 => Ok(EncryptionType::None),
+            1 => Ok(EncryptionType::AesGcm),
+            2 => Ok(EncryptionType::ChaCha20Poly1305),
+            _ => Err(VelesError::CorruptedData),
+        }
+    }
+
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            EncryptionType::None => unreachable!("plaintext rows have no AEAD algorithm"),
+            EncryptionType::AesGcm => &aead::AES_256_GCM,
+            EncryptionType::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// The AEAD key derived from a store's passphrase, plus the algorithm it was
+/// derived for.
+struct EncryptionKey {
+    encryption_type: EncryptionType,
+    key: LessSafeKey,
+}
+
+/// Bytes of the Argon2id-derived key, matching both supported AEADs' key
+/// size.
+const KEY_SIZE: usize = 32;
+
+/// Bytes of salt persisted once in the [`StoreHeader`], and of the
+/// per-row random nonce written ahead of each row's ciphertext.
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+/// Identifies a `.veles/veles.N.db` segment so a stray file isn't misread
+/// as one.
+const STORE_MAGIC: &[u8; 4] = b"VLS1";
+
+/// Bound, in bytes, on how large a single segment is allowed to grow
+/// before writes roll over to a new one.
+const MAX_SEGMENT_SIZE: u64 = 128 * 1024 * 1024;
+
+/// The version a segment's own [`SegmentHeader`] is written with. Bump
+/// this and add a step to [`upgrade_segments`] whenever the fixed
+/// preamble before [`StoreHeader`] changes shape; `crate::format::upgrade`
+/// is what actually drives the migration, once `crate::format::CURRENT_VERSION`
+/// is bumped alongside it.
+const SEGMENT_FORMAT_VERSION: u16 = 2;
+
+/// Every segment was written with this single, unvalidated version byte
+/// before [`SegmentHeader`] existed. Recognized only by [`upgrade_segments`]
+/// so a pre-existing repo can be migrated in place.
+const LEGACY_SEGMENT_VERSION_BYTE: u8 = 1;
+
+/// A segment's own fixed-width header, written right after [`STORE_MAGIC`]:
+/// an explicit, validated version (as opposed to the single hardcoded byte
+/// earlier builds wrote and never checked) plus a reserved flags byte for
+/// future use.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SegmentHeader {
+    version: u16,
+    flags: u8,
+}
+
+fn segment_header_size() -> usize {
+    bincode::serialized_size(&SegmentHeader { version: 0, flags: 0 })
+        .expect("SegmentHeader always has a fixed serialized size") as usize
+}
+
+/// The store-wide header written once at the start of the log: which AEAD
+/// (if any) rows are sealed with, and the salt used to derive its key from
+/// a passphrase. Individual rows additionally carry their own
+/// `encryption_type` so a log that predates encryption being turned on
+/// keeps decoding correctly alongside newer, encrypted rows.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct StoreHeader {
+    encryption_type: i16,
+    salt: [u8; SALT_SIZE],
+}
+
+fn store_header_size() -> usize {
+    bincode::serialized_size(&StoreHeader {
+        encryption_type: 0,
+        salt: [0; SALT_SIZE],
+    })
+    .expect("StoreHeader always has a fixed serialized size") as usize
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 struct RowHeader {
     timestamp: u32,
@@ -18,83 +125,367 @@ struct RowHeader {
     value_size: u32,
     key_type: i16,
     value_type: i16,
+    encryption_type: i16,
 }
 
-const HEADER_SIZE: usize = std::mem::size_of::<RowHeader>();
+/// The on-disk size in bytes of a bincode-encoded `RowHeader`. This is
+/// computed from a reference instance (bincode's fixed-width integer
+/// encoding makes the size independent of the header's actual values)
+/// rather than taken from `std::mem::size_of`, since Rust is free to pad a
+/// struct's in-memory layout in a way bincode's wire format never does.
+fn row_header_size() -> usize {
+    bincode::serialized_size(&RowHeader {
+        timestamp: 0,
+        key_size: 0,
+        value_size: 0,
+        key_type: 0,
+        value_type: 0,
+        encryption_type: 0,
+    })
+    .expect("RowHeader always has a fixed serialized size") as usize
+}
+
+/// `value_type` recorded for an ordinary row, as opposed to the reserved
+/// [`TOMBSTONE_VALUE_TYPE`] written by `delete`.
+const VALUE_TYPE_STRING: i16 = 1;
+
+/// Reserved `value_type` sentinel marking a row as a tombstone: the key is
+/// still recorded (so its framing and CRC/AEAD tag verify like any other
+/// row) but it carries no value. Replay treats it as "this key was
+/// deleted" rather than inserting it into the cache, and `compact` never
+/// carries a tombstoned key's old value (or the tombstone itself) forward.
+const TOMBSTONE_VALUE_TYPE: i16 = -1;
 
 struct VelesStoreEntry {
     pub file: PathBuf,
     pub total_size: u32,
     pub file_offset: u32,
+    pub timestamp: u32,
+    /// Offset of the row's nonce within the file. Recorded only for an
+    /// encrypted row found by scanning its segment; `None` for plaintext
+    /// rows and for rows recorded by an append, a compaction or a hint
+    /// load, whose payload `get` finds right after the row header instead.
+    pub nonce_offset: Option<u32>,
+}
+
+/// One record in the `.veles/veles.hint` file: everything needed to
+/// reconstruct a live key's `VelesStoreEntry` without rereading or
+/// re-verifying its row in its segment.
+#[derive(Serialize, Deserialize)]
+struct HintRecord {
+    key: String,
+    segment: u32,
+    total_size: u32,
+    file_offset: u32,
+    timestamp: u32,
+}
+
+/// Written once at the start of `.veles/veles.hint`: the segment and
+/// within-segment offset the hint was flushed at. `LogStore::new` only
+/// trusts the hint if that segment still exists and hasn't shrunk; any
+/// segment after it is entirely new and gets scanned in full, and the tail
+/// of the recorded segment (written since the hint was flushed) is scanned
+/// too.
+#[derive(Serialize, Deserialize)]
+struct HintHeader {
+    segment: u32,
+    tail_offset: u64,
+}
+
+fn hint_header_size() -> usize {
+    bincode::serialized_size(&HintHeader {
+        segment: 0,
+        tail_offset: 0,
+    })
+    .expect("HintHeader always has a fixed serialized size") as usize
+}
+
+/// The path of the `n`th segment of the log, e.g. `.veles/veles.0.db`.
+fn segment_path(segment: u32) -> PathBuf {
+    PathBuf::from(format!(".veles/veles.{}.db", segment))
+}
+
+/// The segment number a (non-temporary) `veles.N.db` path was built with,
+/// recovered by parsing its file name.
+fn segment_number(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("veles.")?
+        .strip_suffix(".db")?
+        .parse()
+        .ok()
+}
+
+/// The numbers of every existing segment, in ascending (and therefore
+/// write-chronological) order.
+fn list_segments() -> Result<Vec<u32>, VelesError> {
+    let mut segments: Vec<u32> = fs::read_dir(".veles")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| segment_number(&entry.path()))
+        .collect();
+
+    segments.sort_unstable();
+
+    Ok(segments)
+}
+
+/// A key/value storage engine, abstracted so changeset/tree access doesn't
+/// need to care whether a repo's data lives in the append-only
+/// [`LogStore`] or a [`SqliteStore`] table. Lets a repo migrate between
+/// engines (see [`convert`]) without its call sites changing.
+pub trait Storage {
+    fn get(&self, key: &str) -> Result<String, VelesError>;
+    fn put(&mut self, key: &str, value: &str) -> Result<(), VelesError>;
+    fn delete(&mut self, key: &str) -> Result<(), VelesError>;
+    fn compact(&mut self) -> Result<(), VelesError>;
+
+    /// Every key currently present, in no particular order.
+    fn keys(&self) -> Result<Vec<String>, VelesError>;
+}
+
+/// How aggressively appended rows are made durable before `put`/`delete`
+/// return, trading syscall overhead against how much a crash can lose.
+/// Independent of `get`'s visibility of recent writes, which is always
+/// guaranteed by [`LogStore`]'s in-memory map of rows not yet flushed out
+/// of `write_file`'s buffer, regardless of policy.
+pub enum SyncPolicy {
+    /// Never `fsync`s on its own; relies on the OS to flush its page cache
+    /// in its own time. Callers that need a durability checkpoint call
+    /// [`LogStore::flush`] themselves.
+    SyncNever,
+    /// `fsync`s after every `n`th append.
+    SyncEveryN(u32),
+    /// `fsync`s after every single append.
+    SyncAlways,
 }
 
-pub struct VelesStore {
+impl SyncPolicy {
+    /// Reads `[storage] sync` out of `config` — `always`, or `every:<n>` —
+    /// defaulting to `SyncNever` (matching every earlier build, which
+    /// never called `fsync` at all) if unset or unrecognized.
+    pub fn from_config(config: &crate::config::VelesConfig) -> SyncPolicy {
+        match config.get_string("storage", "sync") {
+            Some("always") => SyncPolicy::SyncAlways,
+            Some(value) => value
+                .strip_prefix("every:")
+                .and_then(|n| n.parse().ok())
+                .map(SyncPolicy::SyncEveryN)
+                .unwrap_or(SyncPolicy::SyncNever),
+            None => SyncPolicy::SyncNever,
+        }
+    }
+}
+
+pub struct LogStore {
     cache: HashMap<String, VelesStoreEntry>,
-    write_file: File,
+    write_file: BufWriter<File>,
+    active_segment: u32,
+    store_header: StoreHeader,
+    encryption: Option<EncryptionKey>,
+    rng: SystemRandom,
+    sync_policy: SyncPolicy,
+    writes_since_sync: u32,
+    // The active segment's logical length: the offset the next row will
+    // land at. `write_file`'s own `metadata().len()` only reflects bytes
+    // the OS has actually seen, which lags behind whatever's still sitting
+    // in its `BufWriter` — tracking this ourselves (the same way `compact`
+    // already does locally) is what lets several writes land between two
+    // flushes without each one caching the same stale offset.
+    write_offset: u64,
+    // Rows appended since `write_file` was last actually flushed out to the
+    // OS — `None` for a pending tombstone. `get` checks here first, since a
+    // fresh file handle opened on `cache`'s recorded offset wouldn't yet see
+    // bytes still sitting in `write_file`'s in-process buffer; this is what
+    // lets appends batch into `write_file` for real instead of flushing
+    // after every single one.
+    pending: HashMap<String, Option<String>>,
 }
 
 // Based on https://riak.com/assets/bitcask-intro.pdf
 // Simple key/value storage using a structured log format.
-impl VelesStore {
-    pub fn new() -> Result<VelesStore, VelesError> {
-        let mut cache = HashMap::new();
+impl LogStore {
+    /// Opens (creating if necessary) the log's segments, `.veles/veles.0.db`
+    /// onward. Pass a passphrase to create a new log encrypted with
+    /// ChaCha20-Poly1305, or to unlock one that was created with one; an
+    /// existing log's own header (shared by every segment) always
+    /// determines whether it's encrypted, so a passphrase passed to an
+    /// unencrypted log is simply unused.
+    pub fn new(passphrase: Option<&str>, sync_policy: SyncPolicy) -> Result<LogStore, VelesError> {
+        crate::format::require_current(&PathBuf::from(".veles"))?;
 
         fs::create_dir_all(".veles")?;
-        let mut file = OpenOptions::new().read(true).open(".veles/veles.db")?;
-        let size = file.metadata()?.len();
-
-        let mut pos = 0;
-        while pos != size {
-            // Get the CRC
-            let mut crc_buf = [0; 4];
-            file.read_exact(&mut crc_buf)?;
-            let crc = u32::from_be_bytes(crc_buf);
-
-            // Decode the header to get the keysize, and valuesize.
-            let mut header_bytes = vec![0; HEADER_SIZE];
-            file.read_exact(&mut header_bytes)?;
-            let header: RowHeader = bincode::deserialize_from(header_bytes.as_slice())?;
-
-            // Read the key and value bytes.
-            let mut key_bytes = vec![0; header.key_size as usize];
-            let mut value_bytes = vec![0; header.value_size as usize];
-
-            file.read_exact(&mut key_bytes)?;
-            file.read_exact(&mut value_bytes)?;
-
-            // Now check the crc.
-            let data = [header_bytes, key_bytes.clone(), value_bytes].concat();
-            let mut hasher = Hasher::new();
-            hasher.update(&data);
-            if hasher.finalize() != crc {
-                return Err(VelesError::CorruptedData);
-            }
 
-            // CRC is good. Store this into our cache.
-            let key = String::from_utf8(key_bytes).unwrap();
-            let total_size = 4 + HEADER_SIZE as u32 + header.key_size + header.value_size;
-            cache.insert(
-                key,
-                VelesStoreEntry {
-                    file: PathBuf::from(".veles/veles.db"),
-                    total_size,
-                    file_offset: pos as u32,
+        let mut segments = list_segments()?;
+
+        let store_header = if let Some(&first) = segments.first() {
+            let mut file = File::open(segment_path(first))?;
+            read_store_header(&mut file)?
+        } else {
+            let header = match passphrase {
+                Some(_) => {
+                    let mut salt = [0; SALT_SIZE];
+                    SystemRandom::new()
+                        .fill(&mut salt)
+                        .map_err(|_| VelesError::CryptoError)?;
+
+                    StoreHeader {
+                        encryption_type: EncryptionType::ChaCha20Poly1305 as i16,
+                        salt,
+                    }
+                }
+                None => StoreHeader {
+                    encryption_type: EncryptionType::None as i16,
+                    salt: [0; SALT_SIZE],
                 },
-            );
+            };
+
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(segment_path(0))?;
+            write_store_header(&mut file, &header)?;
+            segments.push(0);
+
+            header
+        };
+
+        let encryption_type = EncryptionType::from_i16(store_header.encryption_type)?;
+        let encryption = match (encryption_type, passphrase) {
+            (EncryptionType::None, _) => None,
+            (_, None) => return Err(VelesError::PassphraseRequired),
+            (encryption_type, Some(passphrase)) => {
+                Some(derive_key(encryption_type, passphrase, &store_header.salt)?)
+            }
+        };
+
+        let header_end = (STORE_MAGIC.len() + segment_header_size() + store_header_size()) as u64;
+
+        // A fresh, fully caught-up hint lets us skip replaying the log
+        // entirely; a stale-but-not-corrupt one still saves us from
+        // rescanning anything but the segments written since it was
+        // flushed (and the tail of the segment it was flushed mid-way
+        // through). Anything else (missing, or the recorded segment
+        // missing or shorter than recorded) falls back to a full scan.
+        let hint_path = PathBuf::from(".veles/veles.hint");
+        let (mut cache, resume_segment, resume_offset, full_rescan) =
+            match load_hint(&hint_path, &segments) {
+                Some((cache, segment, offset)) => (cache, segment, offset, false),
+                None => (HashMap::new(), segments[0], header_end, true),
+            };
+
+        let mut any_scanned = false;
+
+        for &segment in &segments {
+            if segment < resume_segment {
+                continue;
+            }
+
+            let path = segment_path(segment);
+            let mut file = OpenOptions::new().read(true).open(&path)?;
+            let size = file.metadata()?.len();
+            let start = if segment == resume_segment {
+                resume_offset
+            } else {
+                header_end
+            };
+
+            any_scanned = any_scanned || start < size;
+
+            file.seek(std::io::SeekFrom::Start(start))?;
+            scan_rows(&mut file, &path, start, size, &encryption, &mut cache)?;
+        }
+
+        let active_segment = *segments.last().unwrap();
+        let write_file = BufWriter::new(OpenOptions::new().append(true).open(segment_path(active_segment))?);
+        let write_offset = write_file.get_ref().metadata()?.len();
+
+        let store = LogStore {
+            cache,
+            write_file,
+            active_segment,
+            store_header,
+            encryption,
+            rng: SystemRandom::new(),
+            sync_policy,
+            writes_since_sync: 0,
+            write_offset,
+            pending: HashMap::new(),
+        };
 
-            // Increment our position.
-            pos += total_size as u64;
+        if full_rescan {
+            store.flush_hint()?;
+        } else if !any_scanned {
+            // The hint was already fully caught up, so the loop above
+            // scanned nothing — which means no row's CRC (and, for an
+            // encrypted store, AEAD tag) has been checked this open. Read
+            // one row back to surface a wrong passphrase or corruption
+            // immediately, the same as a full rescan would, rather than
+            // waiting for the first real `get`.
+            if let Some(key) = store.cache.keys().next() {
+                store.get(key)?;
+            }
         }
 
-        let write_file = OpenOptions::new()
+        Ok(store)
+    }
+
+    /// Flushes `cache` to `.veles/veles.hint`, so the next `LogStore::new`
+    /// can load it straight from the hint instead of replaying every
+    /// segment.
+    fn flush_hint(&self) -> Result<(), VelesError> {
+        let tail_offset = self.write_file.get_ref().metadata()?.len();
+        write_hint(self.active_segment, tail_offset, &self.cache)
+    }
+
+    /// Forces durability regardless of `sync_policy`: flushes any bytes
+    /// still buffered in `write_file` out to the OS and `fsync`s the
+    /// active segment. Meant for `SyncPolicy::SyncNever` (or `SyncEveryN`
+    /// between its own checkpoints) callers that want a durability
+    /// guarantee on their own schedule, e.g. before reporting a commit as
+    /// successful.
+    pub fn flush(&mut self) -> Result<(), VelesError> {
+        self.write_file.flush()?;
+        self.write_file.get_ref().sync_data()?;
+        self.writes_since_sync = 0;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Closes the active segment off (it becomes immutable) and starts
+    /// appending to a new one.
+    fn roll_segment(&mut self) -> Result<(), VelesError> {
+        // The outgoing segment's buffered rows need to be out on the OS
+        // before anything reads it as an immutable, finished segment.
+        self.write_file.flush()?;
+        self.pending.clear();
+
+        self.active_segment += 1;
+        let path = segment_path(self.active_segment);
+
+        let mut file = OpenOptions::new()
             .create(true)
-            .append(true)
-            .open(".veles/veles.db")?;
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        write_store_header(&mut file, &self.store_header)?;
+        drop(file);
+
+        self.write_file = BufWriter::new(OpenOptions::new().append(true).open(&path)?);
+        self.write_offset = (STORE_MAGIC.len() + segment_header_size() + store_header_size()) as u64;
 
-        Ok(VelesStore { cache, write_file })
+        Ok(())
     }
 
     pub fn get(&self, key: &str) -> Result<String, VelesError> {
+        if let Some(pending) = self.pending.get(key) {
+            return match pending {
+                Some(value) => Ok(value.clone()),
+                None => Err(VelesError::NotFound),
+            };
+        }
+
         if let Some(hint) = self.cache.get(key) {
             let mut file = OpenOptions::new().read(true).open(&hint.file)?;
 
@@ -111,9 +502,21 @@ impl VelesStore {
             }
 
             // Get the value offset and return the value.
-            let header: RowHeader = bincode::deserialize_from(&data[4..HEADER_SIZE + 4])?;
-            let value_offset = 4 + HEADER_SIZE + header.key_size as usize;
-            let value = String::from_utf8(data[value_offset..].to_vec()).unwrap();
+            let header_size = row_header_size();
+            let header: RowHeader = bincode::deserialize_from(&data[4..header_size + 4])?;
+            let row_encryption = EncryptionType::from_i16(header.encryption_type)?;
+
+            // A recorded nonce offset gives where the payload starts;
+            // without one it starts right after the row header.
+            let payload_start = match hint.nonce_offset {
+                Some(nonce_offset) => (nonce_offset - hint.file_offset) as usize,
+                None => 4 + header_size,
+            };
+            let payload = &data[payload_start..];
+            let plaintext = decrypt_row(&self.encryption, row_encryption, payload)?;
+
+            let value_offset = header.key_size as usize;
+            let value = String::from_utf8(plaintext[value_offset..].to_vec()).unwrap();
             return Ok(value);
         }
 
@@ -121,49 +524,1170 @@ impl VelesStore {
     }
 
     pub fn put(&mut self, key: &str, value: &str) -> Result<(), VelesError> {
-        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.append_row(key, value.as_bytes(), VALUE_TYPE_STRING)
+    }
+
+    /// Appends a tombstone row for `key` and drops it from the in-memory
+    /// cache, so it reads as not found until a later `put` recreates it.
+    pub fn delete(&mut self, key: &str) -> Result<(), VelesError> {
+        self.append_row(key, &[], TOMBSTONE_VALUE_TYPE)?;
+        self.cache.remove(key);
 
-        let key_bytes = key.as_bytes();
-        let value_bytes = value.as_bytes();
+        Ok(())
+    }
 
-        let key_size = key_bytes.len() as u32;
-        let value_size = value_bytes.len() as u32;
+    fn append_row(&mut self, key: &str, value_bytes: &[u8], value_type: i16) -> Result<(), VelesError> {
+        let offset = self.write_offset;
+        let (row, timestamp) = build_row(key, value_bytes, value_type, &self.encryption, &self.rng)?;
+        self.write_file.write_all(&row)?;
+        self.write_offset += row.len() as u64;
 
-        let header = RowHeader {
-            timestamp: epoch.as_secs() as u32,
-            key_size,
-            value_size,
-            key_type: 1,
-            value_type: 1,
+        self.cache.insert(
+            key.to_string(),
+            VelesStoreEntry {
+                file: segment_path(self.active_segment),
+                total_size: row.len() as u32,
+                file_offset: offset as u32,
+                timestamp,
+                nonce_offset: None,
+            },
+        );
+
+        // `write_file` isn't flushed out to the OS below unless the sync
+        // policy calls for it this round, so a fresh `get` can't rely on
+        // `cache`'s offset alone yet — it checks here first.
+        let pending_value = if value_type == TOMBSTONE_VALUE_TYPE {
+            None
+        } else {
+            Some(String::from_utf8(value_bytes.to_vec()).unwrap())
         };
+        self.pending.insert(key.to_string(), pending_value);
 
-        let header_bytes: Vec<u8> = bincode::serialize(&header).unwrap();
-        let data = [&header_bytes, key_bytes, value_bytes].concat();
+        self.writes_since_sync += 1;
 
-        let mut hasher = Hasher::new();
-        hasher.update(&data);
-        let crc = hasher.finalize();
+        match self.sync_policy {
+            SyncPolicy::SyncNever => {}
+            SyncPolicy::SyncAlways => {
+                self.write_file.flush()?;
+                self.write_file.get_ref().sync_data()?;
+                self.pending.clear();
+            }
+            SyncPolicy::SyncEveryN(n) if self.writes_since_sync >= n.max(1) => {
+                self.write_file.flush()?;
+                self.write_file.get_ref().sync_data()?;
+                self.pending.clear();
+                self.writes_since_sync = 0;
+            }
+            SyncPolicy::SyncEveryN(_) => {}
+        }
 
-        self.write_file.write_all(&crc.to_be_bytes())?;
-        self.write_file.write_all(&data)?;
+        if self.write_offset >= MAX_SEGMENT_SIZE {
+            self.roll_segment()?;
+        }
 
         Ok(())
     }
 
+    /// Merges every segment into a freshly written replacement: only the
+    /// latest value for each live key is kept (tombstones and the values
+    /// they shadow are dropped), written out as a new run of segments so a
+    /// store too large for one `MAX_SEGMENT_SIZE` segment still compacts
+    /// into more than one, then renamed over the originals one segment at a
+    /// time.
     pub fn compact(&mut self) -> Result<(), VelesError> {
-        self.write_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(".veles/veles_compact.db")?;
+        let old_segments = list_segments()?;
 
+        // Snapshot every live key's current value before any segment is
+        // touched, so the merge below sees one consistent point in time.
         let keys: Vec<String> = self.cache.keys().cloned().collect();
+        let mut entries = Vec::with_capacity(keys.len());
         for key in keys {
             let value = self.get(&key)?;
-            self.put(&key, &value)?;
+            entries.push((key, value));
+        }
+
+        let mut new_cache = HashMap::new();
+        let mut segment = 0u32;
+        let mut file = create_compact_segment(segment, &self.store_header)?;
+        let mut offset = file.metadata()?.len();
+
+        for (key, value) in entries {
+            let (row, timestamp) =
+                build_row(&key, value.as_bytes(), VALUE_TYPE_STRING, &self.encryption, &self.rng)?;
+            file.write_all(&row)?;
+
+            new_cache.insert(
+                key,
+                VelesStoreEntry {
+                    file: segment_path(segment),
+                    total_size: row.len() as u32,
+                    file_offset: offset as u32,
+                    timestamp,
+                    nonce_offset: None,
+                },
+            );
+
+            offset += row.len() as u64;
+
+            if offset >= MAX_SEGMENT_SIZE {
+                file.sync_data()?;
+                segment += 1;
+                file = create_compact_segment(segment, &self.store_header)?;
+                offset = file.metadata()?.len();
+            }
+        }
+
+        // Every compacted segment must be durably on disk before the
+        // rename below makes it visible, or a crash in between could swap
+        // in a segment the OS never actually wrote out.
+        file.sync_data()?;
+        drop(file);
+
+        // Swap the merged segments in for the originals. Each rename below
+        // is atomic on its own and replaces any old segment at that index
+        // in place, but the swap as a whole is not: a crash mid-loop leaves
+        // a mix of old and already-swapped-in new segments rather than a
+        // gap. Only once every new segment is
+        // visible under its real name are the leftover old segments beyond
+        // the new run (if compaction produced fewer segments than before)
+        // removed.
+        for n in 0..=segment {
+            fs::rename(compact_segment_path(n), segment_path(n))?;
+        }
+
+        for old in old_segments {
+            if old > segment {
+                fs::remove_file(segment_path(old))?;
+            }
+        }
+
+        self.cache = new_cache;
+        self.active_segment = segment;
+        self.write_file = BufWriter::new(OpenOptions::new().append(true).open(segment_path(segment))?);
+        self.write_offset = offset;
+        self.writes_since_sync = 0;
+        self.pending.clear();
+
+        self.flush_hint()?;
+
+        Ok(())
+    }
+}
+
+impl Drop for LogStore {
+    /// Leaves a fresh hint behind on a clean shutdown, so the next open
+    /// skips replaying the log's segments. Best-effort: a `Drop` impl can't
+    /// propagate an error, and an open on a dirty shutdown (hint missing or
+    /// behind) just falls back to a full or partial scan anyway.
+    fn drop(&mut self) {
+        let _ = self.flush_hint();
+    }
+}
+
+impl Storage for LogStore {
+    fn get(&self, key: &str) -> Result<String, VelesError> {
+        LogStore::get(self, key)
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> Result<(), VelesError> {
+        LogStore::put(self, key, value)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), VelesError> {
+        LogStore::delete(self, key)
+    }
+
+    fn compact(&mut self) -> Result<(), VelesError> {
+        LogStore::compact(self)
+    }
+
+    fn keys(&self) -> Result<Vec<String>, VelesError> {
+        Ok(self.cache.keys().cloned().collect())
+    }
+}
+
+/// A key/value storage engine backed by a single SQLite table, as an
+/// alternative to [`LogStore`]'s append-only log. Reuses the same
+/// `rusqlite::Connection` conventions as [`crate::dao::VelesDAO`], in its
+/// own `.veles/veles_kv.db3` file so the two don't share a schema.
+pub struct SqliteStore {
+    db: Connection,
+}
+
+impl SqliteStore {
+    pub fn new() -> Result<SqliteStore, VelesError> {
+        fs::create_dir_all(".veles")?;
+        let db = Connection::open(".veles/veles_kv.db3")?;
+
+        db.execute(
+            "CREATE TABLE IF NOT EXISTS kv (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+            (),
+        )?;
+
+        Ok(SqliteStore { db })
+    }
+}
+
+impl Storage for SqliteStore {
+    fn get(&self, key: &str) -> Result<String, VelesError> {
+        let result = self
+            .db
+            .query_row("SELECT value FROM kv WHERE key = ?1", [key], |row| row.get::<_, String>(0));
+
+        match result {
+            Ok(value) => Ok(value),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(VelesError::NotFound),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> Result<(), VelesError> {
+        self.db.execute(
+            "INSERT INTO kv (key, value) VALUES (:key, :value)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            named_params! { ":key": key, ":value": value },
+        )?;
+
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), VelesError> {
+        self.db.execute("DELETE FROM kv WHERE key = ?1", [key])?;
+
+        Ok(())
+    }
+
+    /// A no-op beyond reclaiming deleted rows' space: unlike `LogStore`,
+    /// there are no tombstones or stale values left lying around for a
+    /// SQLite table to merge away.
+    fn compact(&mut self) -> Result<(), VelesError> {
+        self.db.execute("VACUUM", ())?;
+
+        Ok(())
+    }
+
+    fn keys(&self) -> Result<Vec<String>, VelesError> {
+        let mut statement = self.db.prepare("SELECT key FROM kv")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        let result: Result<Vec<_>, _> = rows.collect();
+
+        Ok(result?)
+    }
+}
+
+/// Which [`Storage`] engine a repo's key/value data is persisted with.
+pub enum StorageBackend {
+    /// The original append-only Bitcask-style log, [`LogStore`].
+    Log,
+    /// A single SQLite table, [`SqliteStore`].
+    Sqlite,
+}
+
+impl StorageBackend {
+    /// Reads `[storage] backend` out of `config`, defaulting to `Log` if
+    /// unset. An unrecognized value falls back to `Log` too, rather than
+    /// failing open, since validating the rest of the config file isn't
+    /// this store's job.
+    pub fn from_config(config: &crate::config::VelesConfig) -> StorageBackend {
+        match config.get_string("storage", "backend") {
+            Some("sqlite") => StorageBackend::Sqlite,
+            _ => StorageBackend::Log,
+        }
+    }
+
+    /// Opens this backend, creating it if necessary. `passphrase` and
+    /// `sync_policy` are only meaningful for [`StorageBackend::Log`]; see
+    /// [`LogStore::new`].
+    pub fn open(&self, passphrase: Option<&str>, sync_policy: SyncPolicy) -> Result<Box<dyn Storage>, VelesError> {
+        match self {
+            StorageBackend::Log => Ok(Box::new(LogStore::new(passphrase, sync_policy)?)),
+            StorageBackend::Sqlite => Ok(Box::new(SqliteStore::new()?)),
+        }
+    }
+}
+
+/// Bulk-copies every key from `source` into `destination`, so a repo can
+/// migrate its key/value data between backends (e.g. `log` to `sqlite`).
+/// `destination` isn't cleared first, so converting into a backend that
+/// already holds data merges rather than replaces it.
+pub fn convert(source: &dyn Storage, destination: &mut dyn Storage) -> Result<(), VelesError> {
+    for key in source.keys()? {
+        let value = source.get(&key)?;
+        destination.put(&key, &value)?;
+    }
+
+    Ok(())
+}
+
+/// Loads `.veles/veles.hint` if it's usable: present, parseable, and
+/// recorded against a segment that still exists and hasn't shrunk since
+/// (which should never happen, and means the hint can't be trusted).
+/// Returns the loaded cache together with the segment and within-segment
+/// offset it was flushed at, so the caller knows what (if anything) still
+/// needs to be scanned to catch up: the tail of that segment, plus any
+/// segment after it in full.
+fn load_hint(
+    hint_path: &PathBuf,
+    segments: &[u32],
+) -> Option<(HashMap<String, VelesStoreEntry>, u32, u64)> {
+    let bytes = fs::read(hint_path).ok()?;
+    if bytes.len() < hint_header_size() {
+        return None;
+    }
+
+    let mut cursor = std::io::Cursor::new(&bytes);
+    let header: HintHeader = bincode::deserialize_from(&mut cursor).ok()?;
+
+    if !segments.contains(&header.segment) {
+        return None;
+    }
+    let segment_len = fs::metadata(segment_path(header.segment)).ok()?.len();
+    if header.tail_offset > segment_len {
+        return None;
+    }
+
+    let mut cache = HashMap::new();
+    while (cursor.position() as usize) < bytes.len() {
+        let record: HintRecord = bincode::deserialize_from(&mut cursor).ok()?;
+        cache.insert(
+            record.key,
+            VelesStoreEntry {
+                file: segment_path(record.segment),
+                total_size: record.total_size,
+                file_offset: record.file_offset,
+                timestamp: record.timestamp,
+                nonce_offset: None,
+            },
+        );
+    }
+
+    Some((cache, header.segment, header.tail_offset))
+}
+
+/// Overwrites `.veles/veles.hint` with one record per entry in `cache`,
+/// tagged with `segment`/`tail_offset` (the active segment, and how far
+/// into it this hint covers) so a later `load_hint` knows exactly how much
+/// of the log it still needs to scan to catch up.
+fn write_hint(segment: u32, tail_offset: u64, cache: &HashMap<String, VelesStoreEntry>) -> Result<(), VelesError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(".veles/veles.hint")?;
+
+    file.write_all(&bincode::serialize(&HintHeader { segment, tail_offset })?)?;
+
+    for (key, entry) in cache {
+        let entry_segment = segment_number(&entry.file)
+            .expect("a cache entry's file is always one of our own numbered segments");
+
+        file.write_all(&bincode::serialize(&HintRecord {
+            key: key.clone(),
+            segment: entry_segment,
+            total_size: entry.total_size,
+            file_offset: entry.file_offset,
+            timestamp: entry.timestamp,
+        })?)?;
+    }
+
+    Ok(())
+}
+
+/// Scans rows from `pos` (the file's current cursor position) up to `end`,
+/// verifying each one's CRC and decrypting it, and inserts one
+/// `VelesStoreEntry` per key into `cache`. Used both for a full scan, from
+/// just after the store header, and to catch up on the suffix of the log
+/// written since the last flushed hint.
+fn scan_rows(
+    file: &mut File,
+    path: &PathBuf,
+    mut pos: u64,
+    end: u64,
+    encryption: &Option<EncryptionKey>,
+    cache: &mut HashMap<String, VelesStoreEntry>,
+) -> Result<(), VelesError> {
+    while pos != end {
+        // Get the CRC
+        let mut crc_buf = [0; 4];
+        file.read_exact(&mut crc_buf)?;
+        let crc = u32::from_be_bytes(crc_buf);
+
+        // Decode the header to get the keysize, and valuesize.
+        let mut header_bytes = vec![0; row_header_size()];
+        file.read_exact(&mut header_bytes)?;
+        let header: RowHeader = bincode::deserialize_from(header_bytes.as_slice())?;
+
+        let row_encryption = EncryptionType::from_i16(header.encryption_type)?;
+        let nonce_offset = if row_encryption == EncryptionType::None {
+            None
+        } else {
+            Some(pos as u32 + 4 + header_bytes.len() as u32)
+        };
+
+        // Read the row's payload (plain key+value, or
+        // nonce+ciphertext), and recover the key it belongs to.
+        let payload_size = payload_size(&header, row_encryption);
+        let mut payload = vec![0; payload_size];
+        file.read_exact(&mut payload)?;
+
+        // Now check the crc.
+        let data = [header_bytes, payload.clone()].concat();
+        let mut hasher = Hasher::new();
+        hasher.update(&data);
+        if hasher.finalize() != crc {
+            return Err(VelesError::CorruptedData);
+        }
+
+        let plaintext = decrypt_row(encryption, row_encryption, &payload)?;
+        let key = String::from_utf8(plaintext[..header.key_size as usize].to_vec()).unwrap();
+
+        // CRC (and, for an encrypted row, the AEAD tag) checked out. A
+        // tombstone shadows whatever this key held before; anything else
+        // replaces it in the cache.
+        let total_size = 4 + data.len() as u32;
+        if header.value_type == TOMBSTONE_VALUE_TYPE {
+            cache.remove(&key);
+        } else {
+            cache.insert(
+                key,
+                VelesStoreEntry {
+                    file: path.clone(),
+                    total_size,
+                    file_offset: pos as u32,
+                    timestamp: header.timestamp,
+                    nonce_offset,
+                },
+            );
         }
 
-        fs::rename(".veles/veles_compact.db", ".veles/veles.db")?;
+        // Increment our position.
+        pos += total_size as u64;
+    }
+
+    Ok(())
+}
+
+fn write_store_header(file: &mut File, header: &StoreHeader) -> Result<(), VelesError> {
+    file.write_all(STORE_MAGIC)?;
+    file.write_all(&bincode::serialize(&SegmentHeader {
+        version: SEGMENT_FORMAT_VERSION,
+        flags: 0,
+    })?)?;
+    file.write_all(&bincode::serialize(header)?)?;
+
+    Ok(())
+}
+
+/// The temporary path a compacted replacement for the `n`th segment is
+/// written under, before the atomic rename that makes it (and every other
+/// compacted segment) visible under its real `veles.N.db` name.
+fn compact_segment_path(segment: u32) -> PathBuf {
+    PathBuf::from(format!(".veles/veles.compact.{}.db", segment))
+}
+
+/// Creates (overwriting any leftovers from a previous, interrupted
+/// compaction) the `n`th compacted segment and writes its store header.
+fn create_compact_segment(segment: u32, header: &StoreHeader) -> Result<File, VelesError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(compact_segment_path(segment))?;
+    write_store_header(&mut file, header)?;
+
+    Ok(file)
+}
+
+/// Builds one complete row — CRC, header, and (possibly encrypted)
+/// payload — ready to append to a segment, along with the timestamp it was
+/// stamped with.
+fn build_row(
+    key: &str,
+    value_bytes: &[u8],
+    value_type: i16,
+    encryption: &Option<EncryptionKey>,
+    rng: &SystemRandom,
+) -> Result<(Vec<u8>, u32), VelesError> {
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let key_bytes = key.as_bytes();
+
+    let encryption_type = encryption
+        .as_ref()
+        .map_or(EncryptionType::None, |encryption| encryption.encryption_type);
+
+    let header = RowHeader {
+        timestamp: epoch.as_secs() as u32,
+        key_size: key_bytes.len() as u32,
+        value_size: value_bytes.len() as u32,
+        key_type: 1,
+        value_type,
+        encryption_type: encryption_type as i16,
+    };
+
+    let header_bytes: Vec<u8> = bincode::serialize(&header).unwrap();
+
+    let payload = match encryption {
+        Some(encryption) => {
+            let mut nonce_bytes = [0; NONCE_SIZE];
+            rng.fill(&mut nonce_bytes).map_err(|_| VelesError::CryptoError)?;
+
+            let mut in_out = [key_bytes, value_bytes].concat();
+            let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+            encryption
+                .key
+                .seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out)
+                .map_err(|_| VelesError::CryptoError)?;
+
+            [&nonce_bytes[..], &in_out].concat()
+        }
+        None => [key_bytes, value_bytes].concat(),
+    };
+
+    let data = [&header_bytes, payload.as_slice()].concat();
+
+    let mut hasher = Hasher::new();
+    hasher.update(&data);
+    let crc = hasher.finalize();
+
+    Ok(([&crc.to_be_bytes()[..], &data].concat(), header.timestamp))
+}
+
+fn read_store_header(file: &mut File) -> Result<StoreHeader, VelesError> {
+    let mut magic = [0; STORE_MAGIC.len()];
+    file.read_exact(&mut magic)?;
+    if &magic != STORE_MAGIC {
+        return Err(VelesError::CorruptedData);
+    }
+
+    let mut segment_header_bytes = vec![0; segment_header_size()];
+    file.read_exact(&mut segment_header_bytes)?;
+    let segment_header: SegmentHeader = bincode::deserialize_from(segment_header_bytes.as_slice())?;
+
+    if segment_header.version > SEGMENT_FORMAT_VERSION {
+        return Err(VelesError::UnsupportedVersion(segment_header.version as u32));
+    }
+    if segment_header.version < SEGMENT_FORMAT_VERSION {
+        return Err(VelesError::OutdatedVersion(segment_header.version as u32));
+    }
+
+    let mut header_bytes = vec![0; store_header_size()];
+    file.read_exact(&mut header_bytes)?;
+
+    Ok(bincode::deserialize_from(header_bytes.as_slice())?)
+}
+
+/// Rewrites every segment still at the original, unvalidated single-byte
+/// version (`magic` + a hardcoded [`LEGACY_SEGMENT_VERSION_BYTE`]) into the
+/// current [`SegmentHeader`] layout, leaving [`StoreHeader`] and every
+/// row's bytes untouched — only the fixed preamble before `StoreHeader`
+/// grows to fit the new header. Already-migrated segments (left behind by
+/// a previous, interrupted run of this) are skipped rather than rewritten
+/// again. Called from [`crate::format::upgrade`] once the repo-wide
+/// version is behind `crate::format::CURRENT_VERSION`.
+pub(crate) fn upgrade_segments() -> Result<(), VelesError> {
+    let magic_len = STORE_MAGIC.len();
+
+    for segment in list_segments()? {
+        let path = segment_path(segment);
+        let mut bytes = fs::read(&path)?;
+
+        if bytes.len() < magic_len + 1 || bytes[..magic_len] != STORE_MAGIC[..] {
+            return Err(VelesError::CorruptedData);
+        }
+
+        if bytes[magic_len] != LEGACY_SEGMENT_VERSION_BYTE {
+            continue;
+        }
+
+        let rest = bytes.split_off(magic_len + 1);
+
+        let mut upgraded = STORE_MAGIC.to_vec();
+        upgraded.extend(bincode::serialize(&SegmentHeader {
+            version: SEGMENT_FORMAT_VERSION,
+            flags: 0,
+        })?);
+        upgraded.extend(rest);
+
+        fs::write(&path, upgraded)?;
+    }
+
+    Ok(())
+}
+
+/// The number of payload bytes following a row's header: the plain
+/// key+value for an unencrypted row, or `[nonce][ciphertext][tag]` for an
+/// encrypted one.
+fn payload_size(header: &RowHeader, row_encryption: EncryptionType) -> usize {
+    let plain_size = header.key_size as usize + header.value_size as usize;
+
+    match row_encryption {
+        EncryptionType::None => plain_size,
+        encryption_type => NONCE_SIZE + plain_size + encryption_type.algorithm().tag_len(),
+    }
+}
+
+/// Decrypts (or, for a plaintext row, simply returns) a row's payload.
+/// Rejects an encrypted row if the wrong passphrase was supplied, or if the
+/// row's own `encryption_type` doesn't match the key this store was opened
+/// with (which would mean the passphrase changed, or the log mixes AEADs).
+fn decrypt_row(
+    encryption: &Option<EncryptionKey>,
+    row_encryption: EncryptionType,
+    payload: &[u8],
+) -> Result<Vec<u8>, VelesError> {
+    if row_encryption == EncryptionType::None {
+        return Ok(payload.to_vec());
+    }
+
+    let encryption = encryption
+        .as_ref()
+        .filter(|encryption| encryption.encryption_type == row_encryption)
+        .ok_or(VelesError::PassphraseRequired)?;
+
+    let nonce_bytes: [u8; NONCE_SIZE] = payload[..NONCE_SIZE].try_into()?;
+    let mut in_out = payload[NONCE_SIZE..].to_vec();
+
+    let nonce = Nonce::assume_unique_for_key(nonce_bytes);
+    let plaintext = encryption
+        .key
+        .open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| VelesError::WrongPassphrase)?;
+
+    Ok(plaintext.to_vec())
+}
+
+/// Derives a `KEY_SIZE`-byte AEAD key from `passphrase` and `salt` with
+/// Argon2id, and binds it to `encryption_type`'s algorithm.
+fn derive_key(
+    encryption_type: EncryptionType,
+    passphrase: &str,
+    salt: &[u8; SALT_SIZE],
+) -> Result<EncryptionKey, VelesError> {
+    let mut key_bytes = [0; KEY_SIZE];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key_bytes)
+        .map_err(|_| VelesError::CryptoError)?;
+
+    let unbound = UnboundKey::new(encryption_type.algorithm(), &key_bytes)
+        .map_err(|_| VelesError::CryptoError)?;
+
+    Ok(EncryptionKey {
+        encryption_type,
+        key: LessSafeKey::new(unbound),
+    })
+}
+
+/// Bound on how many deltas may be stacked on top of a snapshot before a
+/// new full snapshot is forced, so reconstruction cost stays bounded.
+const MAX_CHAIN_LEN: u32 = 32;
+
+/// The size of the blocks used to find matching runs between a base and
+/// target revision when building a delta.
+const DELTA_BLOCK_SIZE: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RevlogEntry {
+    base_hash: Option<String>,
+    offset: u64,
+    compressed_len: u32,
+    full_len: u32,
+    chain_len: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+enum DeltaOp {
+    Copy { offset: u32, len: u32 },
+    Insert(Vec<u8>),
+}
+
+/// A revlog-style object store: each revision of a file is recorded as
+/// either a full snapshot or a delta against a previously stored revision,
+/// appended to a single per-file data file and indexed by content hash.
+///
+/// Based on the storage model used by Mercurial's revlogs.
+pub struct Revlog {
+    index_path: PathBuf,
+    data_path: PathBuf,
+    entries: HashMap<String, RevlogEntry>,
+}
+
+impl Revlog {
+    /// Opens (creating if necessary) the revlog identified by `name`,
+    /// typically a stable hash of the file path it stores revisions for.
+    pub fn open(name: &str) -> Result<Revlog, VelesError> {
+        let dir = PathBuf::from(".veles/objects/revlog");
+        fs::create_dir_all(&dir)?;
+
+        let index_path = dir.join(format!("{}.idx", name));
+        let data_path = dir.join(format!("{}.dat", name));
+
+        let entries = if index_path.exists() {
+            let data = fs::read(&index_path)?;
+            bincode::deserialize(&data)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Revlog {
+            index_path,
+            data_path,
+            entries,
+        })
+    }
+
+    fn save_index(&self) -> Result<(), VelesError> {
+        let data = bincode::serialize(&self.entries)?;
+        fs::write(&self.index_path, data)?;
 
         Ok(())
     }
+
+    /// Stores `content` as a new revision, delta-encoded against `parent`
+    /// (the hash of a previously stored revision) when that keeps the
+    /// result smaller and the chain isn't already too deep. Returns the
+    /// SHA-256 hash of `content`, which is already stored under if this
+    /// exact content has been seen before.
+    pub fn write_revision(
+        &mut self,
+        content: &[u8],
+        parent: Option<&str>,
+    ) -> Result<String, VelesError> {
+        let hash = sha256_hex(content);
+
+        if self.entries.contains_key(&hash) {
+            return Ok(hash);
+        }
+
+        let parent_entry = parent.and_then(|p| self.entries.get(p).cloned());
+
+        let (base_hash, payload, chain_len) = match &parent_entry {
+            Some(base) if base.chain_len < MAX_CHAIN_LEN => {
+                let base_content = self.read_revision_unchecked(parent.unwrap())?;
+                let delta = encode_delta(&base_content, content);
+
+                if delta.len() < content.len() {
+                    (Some(parent.unwrap().to_string()), delta, base.chain_len + 1)
+                } else {
+                    (None, content.to_vec(), 0)
+                }
+            }
+            _ => (None, content.to_vec(), 0),
+        };
+
+        let compressed = gzip_compress(&payload)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.data_path)?;
+        let offset = file.metadata()?.len();
+        file.write_all(&compressed)?;
+
+        self.entries.insert(
+            hash.clone(),
+            RevlogEntry {
+                base_hash,
+                offset,
+                compressed_len: compressed.len() as u32,
+                full_len: payload.len() as u32,
+                chain_len,
+            },
+        );
+        self.save_index()?;
+
+        Ok(hash)
+    }
+
+    /// Reconstructs the revision stored under `hash` by walking back to the
+    /// nearest snapshot and replaying deltas forward, then verifies the
+    /// result's SHA-256 matches `hash` before returning it.
+    pub fn read_revision(&self, hash: &str) -> Result<Vec<u8>, VelesError> {
+        let content = self.read_revision_unchecked(hash)?;
+
+        if sha256_hex(&content) != hash {
+            return Err(VelesError::CorruptedData);
+        }
+
+        Ok(content)
+    }
+
+    fn read_revision_unchecked(&self, hash: &str) -> Result<Vec<u8>, VelesError> {
+        let mut chain = Vec::new();
+        let mut current_hash = hash.to_string();
+
+        loop {
+            let entry = self
+                .entries
+                .get(&current_hash)
+                .ok_or(VelesError::NotFound)?
+                .clone();
+
+            let next = entry.base_hash.clone();
+            chain.push(entry);
+
+            match next {
+                Some(base) => current_hash = base,
+                None => break,
+            }
+        }
+
+        chain.reverse();
+
+        let mut content = self.read_payload(&chain[0])?;
+        for entry in &chain[1..] {
+            let delta = self.read_payload(entry)?;
+            content = apply_delta(&content, &delta)?;
+        }
+
+        Ok(content)
+    }
+
+    fn read_payload(&self, entry: &RevlogEntry) -> Result<Vec<u8>, VelesError> {
+        let mut file = OpenOptions::new().read(true).open(&self.data_path)?;
+        file.seek(std::io::SeekFrom::Start(entry.offset))?;
+
+        let mut compressed = vec![0; entry.compressed_len as usize];
+        file.read_exact(&mut compressed)?;
+
+        gzip_decompress(&compressed)
+    }
+}
+
+/// Bytes below which a file is stored as a single object rather than
+/// chunked, since chunking overhead isn't worth it for small content.
+const CDC_MIN_SIZE: usize = 2 * 1024;
+
+/// Hard upper bound on a single chunk's size, forcing a cut even if no
+/// gear-hash boundary is found first.
+const CDC_MAX_SIZE: usize = 64 * 1024;
+
+/// Chunk size at which the chunker switches from the stricter `MASK_SMALL`
+/// to the looser `MASK_LARGE`, targeting an ~8-16 KiB average chunk size.
+const CDC_TARGET_SIZE: usize = 12 * 1024;
+
+/// More bits set, so `hash & MASK_SMALL == 0` is rarer: used before a chunk
+/// reaches `CDC_TARGET_SIZE` to discourage tiny chunks.
+const MASK_SMALL: u64 = (1 << 14) - 1;
+
+/// Fewer bits set than `MASK_SMALL`, so cuts are found more readily: used
+/// past `CDC_TARGET_SIZE` to keep chunks from running up to `CDC_MAX_SIZE`.
+const MASK_LARGE: u64 = (1 << 11) - 1;
+
+/// A marker prefixed to a stored object's (decompressed) payload when it is
+/// a chunk manifest rather than raw content, so `ChunkStore::read` knows to
+/// reassemble it.
+const MANIFEST_MAGIC: &[u8] = b"VCDC1\n";
+
+/// FastCDC-style content-defined chunking: a 64-bit rolling "gear" hash is
+/// updated one byte at a time, and a chunk boundary is declared wherever
+/// the hash happens to have its low bits clear. Because the boundary only
+/// depends on local content, inserting or deleting bytes elsewhere in the
+/// file doesn't shift the other chunk boundaries, so unchanged regions
+/// across revisions hash identically and are only ever stored once.
+struct ContentChunker {
+    gear: [u64; 256],
+}
+
+impl ContentChunker {
+    fn new() -> ContentChunker {
+        // A fixed pseudo-random table (a 64-bit linear congruential
+        // generator) rather than a stored
+        // constant, so there's nothing to keep in sync with this code.
+        let mut gear = [0u64; 256];
+        let mut seed: u64 = 0x9e3779b97f4a7c15;
+        for slot in gear.iter_mut() {
+            seed = seed
+                .wrapping_mul(0x2545_f491_4f6c_dd1d)
+                .wrapping_add(0x9e37_79b9_7f4a_7c15);
+            *slot = seed;
+        }
+
+        ContentChunker { gear }
+    }
+
+    /// Splits `data` into content-defined chunks, each between
+    /// `CDC_MIN_SIZE` and `CDC_MAX_SIZE` bytes (except possibly the last).
+    fn chunks<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+
+        while start < data.len() {
+            let len = self.next_boundary(&data[start..]);
+            chunks.push(&data[start..start + len]);
+            start += len;
+        }
+
+        chunks
+    }
+
+    fn next_boundary(&self, data: &[u8]) -> usize {
+        if data.len() <= CDC_MIN_SIZE {
+            return data.len();
+        }
+
+        let max = data.len().min(CDC_MAX_SIZE);
+        let mut hash: u64 = 0;
+
+        for i in CDC_MIN_SIZE..max {
+            hash = (hash << 1).wrapping_add(self.gear[data[i] as usize]);
+
+            let mask = if i < CDC_TARGET_SIZE {
+                MASK_SMALL
+            } else {
+                MASK_LARGE
+            };
+
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        max
+    }
+}
+
+/// A content-addressed object store with FastCDC chunking: large content is
+/// split into chunks that are stored once each under `.veles/objects/`, and
+/// a manifest listing the ordered chunk hashes is stored under the hash of
+/// the full content, so it reconstructs transparently through the same
+/// `read`/`write` entry points as any other object.
+pub struct ChunkStore;
+
+impl ChunkStore {
+    /// Stores `content` and returns its SHA-256 hash. Content at or below
+    /// `CDC_MIN_SIZE` is stored as a single object; larger content is
+    /// chunked, with each distinct chunk written at most once.
+    pub fn write(content: &[u8]) -> Result<String, VelesError> {
+        let hash = sha256_hex(content);
+
+        if object_path(&hash).exists() {
+            return Ok(hash);
+        }
+
+        if content.len() <= CDC_MIN_SIZE {
+            write_object(&hash, &gzip_compress(content)?)?;
+            return Ok(hash);
+        }
+
+        let chunker = ContentChunker::new();
+        let mut chunk_hashes = Vec::new();
+
+        for chunk in chunker.chunks(content) {
+            let chunk_hash = sha256_hex(chunk);
+
+            if !object_path(&chunk_hash).exists() {
+                write_object(&chunk_hash, &gzip_compress(chunk)?)?;
+            }
+
+            chunk_hashes.push(chunk_hash);
+        }
+
+        let mut payload = MANIFEST_MAGIC.to_vec();
+        payload.extend_from_slice(&bincode::serialize(&chunk_hashes)?);
+        write_object(&hash, &gzip_compress(&payload)?)?;
+
+        Ok(hash)
+    }
+
+    /// Reconstructs the object stored under `hash`, transparently
+    /// reassembling it from chunks if it was stored as a manifest.
+    pub fn read(hash: &str) -> Result<Vec<u8>, VelesError> {
+        let compressed = fs::read(object_path(hash))?;
+        let payload = gzip_decompress(&compressed)?;
+
+        if let Some(rest) = payload.strip_prefix(MANIFEST_MAGIC) {
+            let chunk_hashes: Vec<String> = bincode::deserialize(rest)?;
+            let mut content = Vec::new();
+            for chunk_hash in chunk_hashes {
+                content.extend_from_slice(&ChunkStore::read(&chunk_hash)?);
+            }
+            return Ok(content);
+        }
+
+        Ok(payload)
+    }
+
+    /// Lists every object present under `.veles/objects/`, not including
+    /// the legacy per-file revlog directory, by its on-disk name: shard
+    /// directory plus file name, i.e. the first 40 hex digits of the hash
+    /// for objects this store wrote.
+    pub fn all_hashes() -> Result<Vec<String>, VelesError> {
+        let root = PathBuf::from(".veles/objects");
+        let mut hashes = Vec::new();
+
+        if !root.exists() {
+            return Ok(hashes);
+        }
+
+        for shard in fs::read_dir(&root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() || shard.file_name() == "revlog" {
+                continue;
+            }
+
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                hashes.push(format!(
+                    "{}{}",
+                    shard.file_name().to_string_lossy(),
+                    entry.file_name().to_string_lossy()
+                ));
+            }
+        }
+
+        Ok(hashes)
+    }
+
+    /// Reports whether `hash` is present on disk at all, without decoding
+    /// it.
+    pub fn exists(hash: &str) -> bool {
+        object_path(hash).exists()
+    }
+
+    /// Checks that the object stored under `hash` decompresses and, unless
+    /// it's a chunk manifest, that its content hashes back to `hash` (a
+    /// full hash, or the 40-digit name [`ChunkStore::all_hashes`] lists).
+    /// Returns `Ok(false)` rather than an error for corrupted gzip or
+    /// manifest framing, since that's exactly the corruption fsck looks for.
+    pub fn verify(hash: &str) -> Result<bool, VelesError> {
+        let Ok(compressed) = fs::read(object_path(hash)) else {
+            return Ok(false);
+        };
+
+        let Ok(payload) = gzip_decompress(&compressed) else {
+            return Ok(false);
+        };
+
+        if let Some(rest) = payload.strip_prefix(MANIFEST_MAGIC) {
+            return Ok(bincode::deserialize::<Vec<String>>(rest).is_ok());
+        }
+
+        Ok(sha256_hex(&payload).starts_with(hash))
+    }
+
+    /// Returns the ordered chunk hashes `hash` refers to, if it's a chunk
+    /// manifest, so callers can walk reachability without needing to know
+    /// the manifest's on-disk encoding.
+    pub fn manifest_chunks(hash: &str) -> Result<Option<Vec<String>>, VelesError> {
+        let compressed = fs::read(object_path(hash))?;
+        let payload = gzip_decompress(&compressed)?;
+
+        match payload.strip_prefix(MANIFEST_MAGIC) {
+            Some(rest) => Ok(Some(bincode::deserialize(rest)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn object_path(hash: &str) -> PathBuf {
+    PathBuf::from(".veles/objects/")
+        .join(&hash[..2])
+        .join(&hash[2..40])
+}
+
+fn write_object(hash: &str, compressed: &[u8]) -> Result<(), VelesError> {
+    let dir = PathBuf::from(".veles/objects/").join(&hash[..2]);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(&hash[2..40]), compressed)?;
+
+    Ok(())
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(content);
+    hex::encode(context.finish())
+}
+
+fn gzip_compress(data: &[u8]) -> Result<Vec<u8>, VelesError> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, VelesError> {
+    let mut decoder = GzDecoder::new(data);
+    let mut buf = Vec::new();
+    decoder.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Encodes `target` as a series of copy/insert opcodes against `base`,
+/// using a table of fixed-size block hashes from `base` to find matching
+/// runs (in the spirit of rsync's rolling-checksum delta algorithm).
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut block_offsets: HashMap<&[u8], u32> = HashMap::new();
+    if base.len() >= DELTA_BLOCK_SIZE {
+        for offset in 0..=(base.len() - DELTA_BLOCK_SIZE) {
+            block_offsets
+                .entry(&base[offset..offset + DELTA_BLOCK_SIZE])
+                .or_insert(offset as u32);
+        }
+    }
+
+    let mut ops = Vec::new();
+    let mut literal = Vec::new();
+    let mut pos = 0;
+
+    while pos < target.len() {
+        let block_end = pos + DELTA_BLOCK_SIZE;
+        let matched = if block_end <= target.len() {
+            block_offsets.get(&target[pos..block_end]).copied()
+        } else {
+            None
+        };
+
+        if let Some(base_offset) = matched {
+            if !literal.is_empty() {
+                ops.push(DeltaOp::Insert(std::mem::take(&mut literal)));
+            }
+
+            // Extend the match as far as possible in both buffers.
+            let mut len = DELTA_BLOCK_SIZE;
+            while base_offset as usize + len < base.len()
+                && pos + len < target.len()
+                && base[base_offset as usize + len] == target[pos + len]
+            {
+                len += 1;
+            }
+
+            ops.push(DeltaOp::Copy {
+                offset: base_offset,
+                len: len as u32,
+            });
+            pos += len;
+        } else {
+            literal.push(target[pos]);
+            pos += 1;
+        }
+    }
+
+    if !literal.is_empty() {
+        ops.push(DeltaOp::Insert(literal));
+    }
+
+    bincode::serialize(&ops).expect("delta ops always serialize")
+}
+
+/// Rebuilds a target revision by replaying the copy/insert opcodes in
+/// `delta` against `base`.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, VelesError> {
+    let ops: Vec<DeltaOp> = bincode::deserialize(delta)?;
+    let mut result = Vec::new();
+
+    for op in ops {
+        match op {
+            DeltaOp::Copy { offset, len } => {
+                let start = offset as usize;
+                let end = start + len as usize;
+                result.extend_from_slice(&base[start..end]);
+            }
+            DeltaOp::Insert(bytes) => result.extend_from_slice(&bytes),
+        }
+    }
+
+    Ok(result)
 }
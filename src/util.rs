@@ -1,10 +1,239 @@
 use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
     fs,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
 };
 
 use crate::error::VelesError;
 
+/// The size, in bytes, of the overlapping shingles used to estimate content
+/// similarity between two files.
+const SHINGLE_SIZE: usize = 8;
+
+/// Breaks `data` into a set of hashed, overlapping `SHINGLE_SIZE`-byte
+/// windows ("shingles"), used to estimate how similar two byte strings are
+/// without an expensive full diff.
+fn shingles(data: &[u8]) -> HashSet<u64> {
+    if data.len() < SHINGLE_SIZE {
+        let mut set = HashSet::new();
+        set.insert(hash_bytes(data));
+        return set;
+    }
+
+    (0..=data.len() - SHINGLE_SIZE)
+        .map(|i| hash_bytes(&data[i..i + SHINGLE_SIZE]))
+        .collect()
+}
+
+fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Leading magic bytes recognized as a particular content type, checked in
+/// order against the start of a file's content.
+const MAGIC_TYPES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x7fELF", "application/x-elf"),
+];
+
+/// Extensions recognized as a particular content type when magic-byte
+/// sniffing doesn't match (e.g. plain text formats with no leading magic).
+const EXTENSION_TYPES: &[(&str, &str)] = &[
+    ("rs", "text/x-rust"),
+    ("toml", "text/x-toml"),
+    ("md", "text/markdown"),
+    ("json", "application/json"),
+    ("yml", "text/x-yaml"),
+    ("yaml", "text/x-yaml"),
+    ("txt", "text/plain"),
+];
+
+/// Sniffs a content type from `content`'s leading magic bytes, falling back
+/// to `path`'s extension, and finally to a text/binary guess. Returns the
+/// detected type together with whether it looks like text.
+pub fn detect_content_type(path: &Path, content: &[u8]) -> (String, bool) {
+    if let Some(kind) = sniff_magic(content) {
+        return kind;
+    }
+
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        if let Some(mime) = EXTENSION_TYPES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(ext))
+            .map(|(_, mime)| *mime)
+        {
+            return (mime.to_string(), is_probably_text(content));
+        }
+    }
+
+    guess_from_content(content)
+}
+
+/// Sniffs a content type from `content`'s leading magic bytes alone,
+/// falling back to a text/binary guess. Used where there's no path to fall
+/// back to an extension for, e.g. reconstructing a bare object hash.
+pub fn sniff_content_type(content: &[u8]) -> (String, bool) {
+    sniff_magic(content).unwrap_or_else(|| guess_from_content(content))
+}
+
+fn sniff_magic(content: &[u8]) -> Option<(String, bool)> {
+    MAGIC_TYPES
+        .iter()
+        .find(|(magic, _)| content.starts_with(magic))
+        .map(|(_, mime)| (mime.to_string(), false))
+}
+
+fn guess_from_content(content: &[u8]) -> (String, bool) {
+    if is_probably_text(content) {
+        ("text/plain".to_string(), true)
+    } else {
+        ("application/octet-stream".to_string(), false)
+    }
+}
+
+fn is_probably_text(content: &[u8]) -> bool {
+    std::str::from_utf8(content).is_ok() && !content.contains(&0)
+}
+
+/// Estimates how similar two files are as the Jaccard index of their
+/// shingle sets: the fraction of shingles the two have in common out of
+/// all the shingles either one has. Used to detect renames/copies of
+/// files that were edited slightly along the way.
+pub fn content_similarity(a: &[u8], b: &[u8]) -> f64 {
+    let shingles_a = shingles(a);
+    let shingles_b = shingles(b);
+
+    let union = shingles_a.union(&shingles_b).count();
+    if union == 0 {
+        return 1.0;
+    }
+
+    let intersection = shingles_a.intersection(&shingles_b).count();
+    intersection as f64 / union as f64
+}
+
+/// One compiled `.velesignore` line: the pattern's `/`-separated segments
+/// (each of which may contain `*`/`?` wildcards, or be a literal `**`
+/// matching any number of directories), whether a leading `/` anchors it to
+/// the ignore file's directory rather than matching at any depth, whether a
+/// trailing `/` restricts it to directories, and whether a leading `!`
+/// negates it, re-including a path an earlier pattern excluded.
+struct IgnorePattern {
+    segments: Vec<String>,
+    anchored: bool,
+    dir_only: bool,
+    negate: bool,
+}
+
+impl IgnorePattern {
+    /// Parses one `.velesignore` line, or returns `None` for a blank line or
+    /// a `#` comment.
+    fn parse(line: &str) -> Option<IgnorePattern> {
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return None;
+        }
+
+        let negate = trimmed.starts_with('!');
+        let pattern = if negate { &trimmed[1..] } else { trimmed };
+
+        let anchored = pattern.starts_with('/');
+        let pattern = if anchored { &pattern[1..] } else { pattern };
+
+        let dir_only = pattern.len() > 1 && pattern.ends_with('/');
+        let pattern = if dir_only {
+            &pattern[..pattern.len() - 1]
+        } else {
+            pattern
+        };
+
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(IgnorePattern {
+            segments: pattern.split('/').map(str::to_string).collect(),
+            anchored,
+            dir_only,
+            negate,
+        })
+    }
+
+    /// Whether this pattern matches `path_segments` (the path being tested,
+    /// relative to the ignore file's directory, split on `/`). An anchored
+    /// pattern must match the whole path; an unanchored one may match
+    /// starting at any depth. A directory-only pattern never matches a file.
+    fn matches(&self, path_segments: &[&str], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            segments_match(&self.segments, path_segments)
+        } else {
+            (0..=path_segments.len())
+                .any(|start| segments_match(&self.segments, &path_segments[start..]))
+        }
+    }
+}
+
+/// Matches a pattern's `/`-separated segments against a path's, where a
+/// literal `**` segment consumes any number (including zero) of path
+/// segments.
+fn segments_match(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((head, rest)) if head == "**" => {
+            segments_match(rest, path) || (!path.is_empty() && segments_match(pattern, &path[1..]))
+        }
+        Some((head, rest)) => match path.split_first() {
+            Some((first, path_rest)) => glob_match(head, first) && segments_match(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+/// Matches a single path segment against a pattern containing `*` (any run
+/// of characters) and `?` (any single character) wildcards.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.first() {
+            None => text.is_empty(),
+            Some('*') => matches(&pattern[1..], text) || (!text.is_empty() && matches(pattern, &text[1..])),
+            Some('?') => !text.is_empty() && matches(&pattern[1..], &text[1..]),
+            Some(c) => text.first() == Some(c) && matches(&pattern[1..], &text[1..]),
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+/// Whether `path_segments` should be skipped given `patterns`, evaluated in
+/// file order with last-match-wins semantics (including negation), matching
+/// `.gitignore`'s rules.
+fn is_ignored(patterns: &[IgnorePattern], path_segments: &[&str], is_dir: bool) -> bool {
+    let mut ignored = false;
+
+    for pattern in patterns {
+        if pattern.matches(path_segments, is_dir) {
+            ignored = !pattern.negate;
+        }
+    }
+
+    ignored
+}
+
 /// Iterates over all files and directories in a given path.
 pub struct DirIterator {
     stack: Vec<PathBuf>,
@@ -13,7 +242,8 @@ pub struct DirIterator {
 
 impl DirIterator {
     /// Iterates over everything in a directory but will ignore
-    /// any items that match the paths in the given ignore file.
+    /// any items that match the glob patterns in the given ignore file.
+    /// An ignored directory is pruned entirely rather than descended into.
     pub fn from_ignorefile<P: AsRef<Path>>(
         base: P,
         ignore: P,
@@ -23,24 +253,24 @@ impl DirIterator {
         let base_path = base.as_ref().to_path_buf();
         let ignore = ignore.as_ref().to_path_buf();
 
-        let filter: Vec<PathBuf> = if ignore.exists() {
-            let ignore_data = fs::read_to_string(ignore)?;
-            ignore_data
+        let patterns: Vec<IgnorePattern> = if ignore.exists() {
+            fs::read_to_string(ignore)?
                 .lines()
-                .map(|line| base_path.join(line))
+                .filter_map(IgnorePattern::parse)
                 .collect()
         } else {
             Vec::new()
         };
 
-        DirIterator::visit(base.as_ref(), &filter, &mut stack, include_dirs)?;
+        DirIterator::visit(&base_path, &base_path, &patterns, &mut stack, include_dirs)?;
 
         Ok(DirIterator { stack, pos: 0 })
     }
 
     fn visit(
+        base: &Path,
         path: &Path,
-        filter: &[PathBuf],
+        patterns: &[IgnorePattern],
         stack: &mut Vec<PathBuf>,
         include_dirs: bool,
     ) -> Result<(), VelesError> {
@@ -48,16 +278,23 @@ impl DirIterator {
             for entry in fs::read_dir(path)? {
                 let entry = entry?;
                 let path = entry.path();
+                let is_dir = path.is_dir();
+
+                let relative = path.strip_prefix(base).unwrap_or(&path);
+                let segments: Vec<&str> = relative
+                    .components()
+                    .filter_map(|component| component.as_os_str().to_str())
+                    .collect();
 
-                if filter.iter().any(|p| path.starts_with(p)) {
+                if is_ignored(patterns, &segments, is_dir) {
                     continue;
                 }
 
-                if path.is_dir() {
+                if is_dir {
                     if include_dirs {
                         stack.push(path.to_path_buf());
                     }
-                    DirIterator::visit(&path, filter, stack, include_dirs)?;
+                    DirIterator::visit(base, &path, patterns, stack, include_dirs)?;
                 } else {
                     stack.push(path.to_path_buf());
                 }